@@ -48,8 +48,11 @@ contract Omnic is QueueManager, OmnicBase {
 
     mapping(address => bool) public whitelisted; // whitelisted addresses can send msg for free
 
+    // new proxy canister address approved by the owner, the proxy canister can switch to it
+    address public pendingProxyCanisterAddr;
+
     // gap for upgrade safety
-    uint256[49] private __GAP;
+    uint256[48] private __GAP;
 
     // ============ modifiers  ============
 
@@ -58,6 +61,15 @@ contract Omnic is QueueManager, OmnicBase {
         _;
     }
 
+    // proxy canister pays relayer rewards from the collected fees
+    modifier onlyOwnerOrProxyCanister() {
+        require(
+            msg.sender == owner() || msg.sender == omnicProxyCanisterAddr,
+            "!ownerOrProxyCanister"
+        );
+        _;
+    }
+
     // ============ Events  ============
     event SendMessage(
         bytes32 indexed messageHash,
@@ -73,11 +85,19 @@ contract Omnic is QueueManager, OmnicBase {
         bool success
     );
 
+    event ProcessBatchEntry(
+        bytes32 indexed messageHash,
+        uint256 index,
+        bool success
+    );
+
     event UpdateProxyCanister(
         address oldProxyCanisterAddr,
         address newProxyCanisterAddr
     );
 
+    event ApproveProxyCanister(address newProxyCanisterAddr);
+
     // ============== Start ===============
     constructor() {
         entered = 1;
@@ -99,50 +119,64 @@ contract Omnic is QueueManager, OmnicBase {
         address payable _refundAddress,
         address _erc20PaymentAddress
     ) public payable {
-        require(_payload.length <= MAX_MESSAGE_BODY_BYTES, "msg too long");
-        // compute all the fees
-        uint256 nativeProtocolFee = _handleProtocolFee(
-            msg.sender,
-            _erc20PaymentAddress,
-            _payload.length
-        );
-
-        // assert whether the user has enough native token amount
-        require(
-            nativeProtocolFee <= msg.value,
-            "Omnic: not enough value for fees"
+        _chargeFee(_payload.length, _refundAddress, _erc20PaymentAddress);
+        uint32 _nonce = _nextNonce(_dstChainId);
+        bytes memory _message = Types.formatMessage(
+            chainId,
+            TypeCasts.addressToBytes32(msg.sender),
+            _nonce,
+            _dstChainId,
+            _recipientAddress,
+            _payload
         );
-        // refund if sent too much
-        uint256 amount = msg.value.sub(nativeProtocolFee);
-        if (amount > 0) {
-            (bool success, ) = _refundAddress.call{value: amount}("");
-            require(success, "Omnic: failed to refund");
-        }
-
-        // get the next nonce for the destination domain, then increment it
-        uint32 _nonce = nonces[_dstChainId];
-        nonces[_dstChainId] = _nonce + 1;
+        _insertMessage(_message, _nonce);
+    }
 
-        bytes memory _message = Types.formatMessage(
+    // send a v1 message with header extensions, e.g. a deadline
+    function sendMessageV1(
+        uint32 _dstChainId,
+        bytes32 _recipientAddress,
+        Types.Extension[] memory _extensions,
+        bytes memory _payload,
+        address payable _refundAddress,
+        address _erc20PaymentAddress
+    ) public payable {
+        _chargeFee(_payload.length, _refundAddress, _erc20PaymentAddress);
+        uint32 _nonce = _nextNonce(_dstChainId);
+        bytes memory _message = Types.formatMessageV1(
             chainId,
             TypeCasts.addressToBytes32(msg.sender),
             _nonce,
             _dstChainId,
             _recipientAddress,
+            _extensions,
             _payload
         );
-        bytes32 _messageHash = keccak256(_message);
-        tree.insert(_messageHash);
-        // enqueue the new Merkle root after inserting the message
-        queue.enqueue(tree.root());
+        _insertMessage(_message, _nonce);
+    }
 
-        emit SendMessage(
-            _messageHash,
-            tree.count - 1,
+    // send several payloads to the same destination under one leaf
+    function sendMessageBatch(
+        uint32 _dstChainId,
+        Types.BatchEntry[] memory _entries,
+        address payable _refundAddress,
+        address _erc20PaymentAddress
+    ) public payable {
+        bytes memory _payload = abi.encode(_entries);
+        _chargeFee(_payload.length, _refundAddress, _erc20PaymentAddress);
+        uint32 _nonce = _nextNonce(_dstChainId);
+        Types.Extension[] memory _extensions = new Types.Extension[](1);
+        _extensions[0] = Types.batch();
+        bytes memory _message = Types.formatMessageV1(
             chainId,
+            TypeCasts.addressToBytes32(msg.sender),
             _nonce,
-            _message
+            _dstChainId,
+            bytes32(0),
+            _extensions,
+            _payload
         );
+        _insertMessage(_message, _nonce);
     }
 
     // fee free function for whitelisted contracts
@@ -154,10 +188,7 @@ contract Omnic is QueueManager, OmnicBase {
     ) public {
         require(whitelisted[msg.sender], "not whitelisted caller");
         require(_payload.length <= MAX_MESSAGE_BODY_BYTES, "msg too long");
-        // get the next nonce for the destination domain, then increment it
-        uint32 _nonce = nonces[_dstChainId];
-        nonces[_dstChainId] = _nonce + 1;
-
+        uint32 _nonce = _nextNonce(_dstChainId);
         bytes memory _message = Types.formatMessage(
             chainId,
             TypeCasts.addressToBytes32(msg.sender),
@@ -166,6 +197,42 @@ contract Omnic is QueueManager, OmnicBase {
             _recipientAddress,
             _payload
         );
+        _insertMessage(_message, _nonce);
+    }
+
+    function _chargeFee(
+        uint256 _payloadLength,
+        address payable _refundAddress,
+        address _erc20PaymentAddress
+    ) internal {
+        require(_payloadLength <= MAX_MESSAGE_BODY_BYTES, "msg too long");
+        // compute all the fees
+        uint256 nativeProtocolFee = _handleProtocolFee(
+            msg.sender,
+            _erc20PaymentAddress,
+            _payloadLength
+        );
+
+        // assert whether the user has enough native token amount
+        require(
+            nativeProtocolFee <= msg.value,
+            "Omnic: not enough value for fees"
+        );
+        // refund if sent too much
+        uint256 amount = msg.value.sub(nativeProtocolFee);
+        if (amount > 0) {
+            (bool success, ) = _refundAddress.call{value: amount}("");
+            require(success, "Omnic: failed to refund");
+        }
+    }
+
+    // get the next nonce for the destination domain, then increment it
+    function _nextNonce(uint32 _dstChainId) internal returns (uint32 _nonce) {
+        _nonce = nonces[_dstChainId];
+        nonces[_dstChainId] = _nonce + 1;
+    }
+
+    function _insertMessage(bytes memory _message, uint32 _nonce) internal {
         bytes32 _messageHash = keccak256(_message);
         tree.insert(_messageHash);
         // enqueue the new Merkle root after inserting the message
@@ -186,27 +253,17 @@ contract Omnic is QueueManager, OmnicBase {
         onlyProxyCanister
         returns (bool success)
     {
-        // decode message
-        (
-            uint32 _srcChainId,
-            bytes32 _srcSenderAddress,
-            uint32 _nonce,
-            uint32 _dstChainId,
-            bytes32 _recipientAddress,
-            bytes memory _payload
-        ) = abi.decode(
-                _message,
-                (uint32, bytes32, uint32, uint32, bytes32, bytes)
-            );
+        // decode message, v0 or v1 envelope
+        Types.Message memory _m = _decodeMessage(_message);
+        require(!Types.isBatch(_m), "!batch");
         bytes32 _messageHash = keccak256(_message);
-        require(_dstChainId == chainId, "!destination");
         // check re-entrancy guard
         require(entered == 1, "!reentrant");
         entered = 0;
 
         // call handle function
-        IOmnicReciver(TypeCasts.bytes32ToAddress(_recipientAddress))
-            .handleMessage(_srcChainId, _srcSenderAddress, _nonce, _payload);
+        IOmnicReciver(TypeCasts.bytes32ToAddress(_m.recipientAddress))
+            .handleMessage(_m.srcChainId, _m.srcSenderAddress, _m.nonce, _m.payload);
         // emit process results
         emit ProcessMessage(_messageHash, "", true);
         // reset re-entrancy guard
@@ -215,6 +272,92 @@ contract Omnic is QueueManager, OmnicBase {
         return true;
     }
 
+    // process a batch message, a failed entry does not revert the others
+    function processMessages(bytes memory _message)
+        public
+        onlyProxyCanister
+        returns (bool success)
+    {
+        Types.Message memory _m = _decodeMessage(_message);
+        require(Types.isBatch(_m), "!batch");
+        bytes32 _messageHash = keccak256(_message);
+        // check re-entrancy guard
+        require(entered == 1, "!reentrant");
+        entered = 0;
+
+        _handleBatch(_m, _messageHash);
+        emit ProcessMessage(_messageHash, "", true);
+        // reset re-entrancy guard
+        entered = 1;
+        return true;
+    }
+
+    // process messages queued by the proxy canister in one tx, a failed message does not revert
+    // the others, the result of each message is in its ProcessMessage event
+    function processMessageBatch(bytes[] memory _messages)
+        public
+        onlyProxyCanister
+        returns (bool[] memory results)
+    {
+        // check re-entrancy guard
+        require(entered == 1, "!reentrant");
+        entered = 0;
+
+        results = new bool[](_messages.length);
+        for (uint256 i = 0; i < _messages.length; i++) {
+            try this.processQueuedMessage(_messages[i]) {
+                results[i] = true;
+            } catch {
+                emit ProcessMessage(keccak256(_messages[i]), "", false);
+            }
+        }
+        // reset re-entrancy guard
+        entered = 1;
+    }
+
+    // one message of processMessageBatch, external so that its failure can be caught
+    function processQueuedMessage(bytes memory _message) external {
+        require(msg.sender == address(this), "!self");
+        Types.Message memory _m = _decodeMessage(_message);
+        bytes32 _messageHash = keccak256(_message);
+        if (Types.isBatch(_m)) {
+            _handleBatch(_m, _messageHash);
+        } else {
+            IOmnicReciver(TypeCasts.bytes32ToAddress(_m.recipientAddress))
+                .handleMessage(_m.srcChainId, _m.srcSenderAddress, _m.nonce, _m.payload);
+        }
+        emit ProcessMessage(_messageHash, "", true);
+    }
+
+    function _handleBatch(Types.Message memory _m, bytes32 _messageHash) internal {
+        Types.BatchEntry[] memory _entries = Types.decodeBatch(_m.payload);
+        for (uint256 i = 0; i < _entries.length; i++) {
+            bool _ok;
+            try IOmnicReciver(TypeCasts.bytes32ToAddress(_entries[i].recipientAddress))
+                .handleMessage(_m.srcChainId, _m.srcSenderAddress, _m.nonce, _entries[i].payload) {
+                _ok = true;
+            } catch {
+                _ok = false;
+            }
+            emit ProcessBatchEntry(_messageHash, i, _ok);
+        }
+    }
+
+    // decode and check the destination and deadline
+    function _decodeMessage(bytes memory _message)
+        internal
+        view
+        returns (Types.Message memory _m)
+    {
+        _m = Types.decodeMessage(_message);
+        require(_m.dstChainId == chainId, "!destination");
+        // the origin block deadline is checked by the proxy canister
+        (bool _hasDeadline, bytes memory _deadline) = Types.getExtension(_m, Types.EXT_DEADLINE_TIMESTAMP);
+        if (_hasDeadline) {
+            require(block.timestamp <= abi.decode(_deadline, (uint64)), "!deadline");
+        }
+    }
+
     function _handleProtocolFee(
         address _srcSenderAddress,
         address _erc20PaymentAddress,
@@ -273,7 +416,7 @@ contract Omnic is QueueManager, OmnicBase {
     // withdraw native token function.
     function withdrawNativeFee(address payable _to, uint256 _amount)
         external
-        onlyOwner
+        onlyOwnerOrProxyCanister
     {
         require(_to != address(0x0), "Omnic: _to cannot be zero address");
         nativeFees = nativeFees.sub(_amount);
@@ -283,10 +426,28 @@ contract Omnic is QueueManager, OmnicBase {
     }
 
     // ============ onlyOwner Set Functions  ============
-    function setOmnicCanisterAddr(address _newProxyCanisterAddr)
+    // pre-approve the address the proxy canister rotates to
+    function approveOmnicCanisterAddr(address _newProxyCanisterAddr)
         public
         onlyOwner
     {
+        pendingProxyCanisterAddr = _newProxyCanisterAddr;
+        emit ApproveProxyCanister(_newProxyCanisterAddr);
+    }
+
+    // owner can set any address, proxy canister can only hand over to the approved one
+    function setOmnicCanisterAddr(address _newProxyCanisterAddr)
+        public
+        onlyOwnerOrProxyCanister
+    {
+        if (msg.sender != owner()) {
+            require(
+                _newProxyCanisterAddr != address(0) &&
+                    _newProxyCanisterAddr == pendingProxyCanisterAddr,
+                "Omnic: new proxy canister not approved"
+            );
+        }
+        pendingProxyCanisterAddr = address(0);
         address _oldProxyCanisterAddr = omnicProxyCanisterAddr;
         omnicProxyCanisterAddr = _newProxyCanisterAddr;
         emit UpdateProxyCanister(_oldProxyCanisterAddr, _newProxyCanisterAddr);
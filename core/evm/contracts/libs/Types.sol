@@ -5,6 +5,40 @@ pragma solidity ^0.8.9;
  * @title Types Library
  **/
 library Types {
+    // high bytes of the header word of versioned messages, the version is the last byte,
+    // v0 messages start with the uint32 origin so their high bytes are zero
+    bytes4 internal constant ENVELOPE_MAGIC = 0x4f4d4e43; // "OMNC"
+    uint8 internal constant MESSAGE_V0 = 0;
+    uint8 internal constant MESSAGE_V1 = 1;
+
+    // header extension kinds
+    uint16 internal constant EXT_DEADLINE_TIMESTAMP = 1; // abi.encode(uint64 unix seconds)
+    uint16 internal constant EXT_DEADLINE_BLOCK = 2; // abi.encode(uint64 origin block number)
+    uint16 internal constant EXT_NOTIFY_EXPIRY = 3; // empty, ask for a notice if the message expires
+    uint16 internal constant EXT_BATCH = 4; // empty, the payload is abi.encode(BatchEntry[])
+
+    // one payload of a batch message, the recipient of a batch message is unused
+    struct BatchEntry {
+        bytes32 recipientAddress;
+        bytes payload;
+    }
+
+    // header extension of a v1 message
+    struct Extension {
+        uint16 kind;
+        bytes data;
+    }
+
+    struct Message {
+        uint8 version;
+        uint32 srcChainId;
+        bytes32 srcSenderAddress;
+        uint32 nonce;
+        uint32 dstChainId;
+        bytes32 recipientAddress;
+        Extension[] extensions;
+        bytes payload;
+    }
 
     /**
      * @notice Returns formatted (packed) message with provided fields
@@ -34,4 +68,159 @@ library Types {
                 _payload
             );
     }
+
+    /**
+     * @notice Returns formatted v1 message, the v0 fields with a version header and header extensions
+     * @param _srcChainId source chain id
+     * @param _srcSenderAddress Address of sender
+     * @param _nonce Destination-specific nonce
+     * @param _dstChainId  destination chain id
+     * @param _recipientAddress Address of recipient on destination chain
+     * @param _extensions Header extensions, e.g. deadline
+     * @param _payload Raw bytes of message body
+     * @return Formatted message
+     **/
+    function formatMessageV1(
+        uint32 _srcChainId,
+        bytes32 _srcSenderAddress,
+        uint32 _nonce,
+        uint32 _dstChainId,
+        bytes32 _recipientAddress,
+        Extension[] memory _extensions,
+        bytes memory _payload
+    ) internal pure returns (bytes memory) {
+        return
+            abi.encode(
+                envelopeHeader(MESSAGE_V1),
+                _srcChainId,
+                _srcSenderAddress,
+                _nonce,
+                _dstChainId,
+                _recipientAddress,
+                _extensions,
+                _payload
+            );
+    }
+
+    function envelopeHeader(uint8 _version) internal pure returns (bytes32) {
+        return bytes32(ENVELOPE_MAGIC) | bytes32(uint256(_version));
+    }
+
+    /**
+     * @notice Returns the envelope version of a formatted message
+     * @param _message Formatted message
+     * @return Version, 0 if the message has no version header
+     **/
+    function messageVersion(bytes memory _message) internal pure returns (uint8) {
+        if (_message.length < 32) {
+            return MESSAGE_V0;
+        }
+        bytes32 _header;
+        assembly {
+            _header := mload(add(_message, 32))
+        }
+        if (bytes4(_header) != ENVELOPE_MAGIC) {
+            return MESSAGE_V0;
+        }
+        return uint8(uint256(_header));
+    }
+
+    /**
+     * @notice Decodes a formatted message of any supported version
+     * @param _message Formatted message
+     * @return m Decoded message, v0 messages have no extensions
+     **/
+    function decodeMessage(bytes memory _message) internal pure returns (Message memory m) {
+        m.version = messageVersion(_message);
+        if (m.version == MESSAGE_V0) {
+            (
+                m.srcChainId,
+                m.srcSenderAddress,
+                m.nonce,
+                m.dstChainId,
+                m.recipientAddress,
+                m.payload
+            ) = abi.decode(_message, (uint32, bytes32, uint32, uint32, bytes32, bytes));
+        } else if (m.version == MESSAGE_V1) {
+            (
+                ,
+                m.srcChainId,
+                m.srcSenderAddress,
+                m.nonce,
+                m.dstChainId,
+                m.recipientAddress,
+                m.extensions,
+                m.payload
+            ) = abi.decode(_message, (bytes32, uint32, bytes32, uint32, uint32, bytes32, Extension[], bytes));
+        } else {
+            revert("!version");
+        }
+    }
+
+    /**
+     * @notice Returns the data of the first extension of `_kind`
+     **/
+    function getExtension(Message memory _m, uint16 _kind) internal pure returns (bool found, bytes memory data) {
+        for (uint256 i = 0; i < _m.extensions.length; i++) {
+            if (_m.extensions[i].kind == _kind) {
+                return (true, _m.extensions[i].data);
+            }
+        }
+    }
+
+    function deadlineTimestamp(uint64 _timestamp) internal pure returns (Extension memory) {
+        return Extension(EXT_DEADLINE_TIMESTAMP, abi.encode(_timestamp));
+    }
+
+    function deadlineBlock(uint64 _blockNumber) internal pure returns (Extension memory) {
+        return Extension(EXT_DEADLINE_BLOCK, abi.encode(_blockNumber));
+    }
+
+    function notifyExpiry() internal pure returns (Extension memory) {
+        return Extension(EXT_NOTIFY_EXPIRY, "");
+    }
+
+    function batch() internal pure returns (Extension memory) {
+        return Extension(EXT_BATCH, "");
+    }
+
+    function isBatch(Message memory _m) internal pure returns (bool found) {
+        (found, ) = getExtension(_m, EXT_BATCH);
+    }
+
+    function decodeBatch(bytes memory _payload) internal pure returns (BatchEntry[] memory) {
+        return abi.decode(_payload, (BatchEntry[]));
+    }
+
+    /**
+     * @notice Returns a typed payload, tag is bytes4(keccak256("Name(type1,type2,..)"))
+     * @param _tag Type tag of the payload schema
+     * @param _data abi encoded fields
+     * @return Message payload
+     **/
+    function formatPayload(bytes4 _tag, bytes memory _data) internal pure returns (bytes memory) {
+        return abi.encode(_tag, _data);
+    }
+
+    /**
+     * @notice Splits a typed payload into its tag and abi encoded fields
+     **/
+    function decodePayload(bytes memory _payload) internal pure returns (bytes4 tag, bytes memory data) {
+        (tag, data) = abi.decode(_payload, (bytes4, bytes));
+    }
+
+    /**
+     * @notice Decodes the payload of an ack sent by an IC app, origin chain id is 0
+     * @param _payload Payload of the ack message
+     * @return msgHash Leaf of the acknowledged message
+     * @return success Whether the app handled the message
+     * @return result Result or error text returned by the app
+     **/
+    function decodeAck(bytes memory _payload)
+        internal
+        pure
+        returns (bytes32 msgHash, bool success, bytes memory result)
+    {
+        (msgHash, success, result) = abi.decode(_payload, (bytes32, bool, bytes));
+    }
 }
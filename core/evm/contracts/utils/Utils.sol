@@ -12,4 +12,14 @@ library TypeCasts {
     function bytes32ToAddress(bytes32 _buf) internal pure returns (address) {
         return address(uint160(uint256(_buf)));
     }
+
+    // ic principal (raw bytes, at most 29) as [len, 0.., principal bytes]
+    function principalToBytes32(bytes memory _principal) internal pure returns (bytes32) {
+        require(_principal.length > 0 && _principal.length <= 29, "!principal");
+        uint256 _buf = uint256(_principal.length) << 248;
+        for (uint256 i = 0; i < _principal.length; i++) {
+            _buf |= uint256(uint8(_principal[i])) << (8 * (_principal.length - 1 - i));
+        }
+        return bytes32(_buf);
+    }
 }
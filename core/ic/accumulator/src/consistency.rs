@@ -0,0 +1,240 @@
+use crate::{hash_concat, proof::const_array_serde, VerifyingError, ZERO_HASHES};
+// use ethers::prelude::H256;
+use ic_web3::types::H256;
+
+/// A proof that the tree of the first `old_count` leaves is a prefix of a tree
+/// of `new_count` leaves.
+///
+/// It is the branch of leaf `old_count` in the new tree. Its left siblings only
+/// cover leaves before `old_count`, so they are shared by both trees, and the
+/// old root is the same branch with that leaf and the right siblings zeroed.
+/// `new_count` is bound to the new root by the branch of the last leaf, whose
+/// right siblings must all be empty.
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyProof<const N: usize> {
+    /// The leaf count of the old tree
+    pub old_count: usize,
+    /// The leaf count of the new tree
+    pub new_count: usize,
+    /// The leaf at `old_count` in the new tree, zero if the counts are equal
+    pub leaf: H256,
+    /// The merkle branch of `leaf` in the new tree
+    #[serde(with = "const_array_serde")]
+    pub path: [H256; N],
+    /// The leaf at `new_count - 1` in the new tree, zero if it is empty
+    pub last: H256,
+    /// The merkle branch of `last` in the new tree
+    #[serde(with = "const_array_serde")]
+    pub last_path: [H256; N],
+}
+
+impl<const N: usize> ConsistencyProof<N> {
+    /// Calculate the root of the old tree
+    pub fn old_root(&self) -> H256 {
+        let mut current = H256::zero();
+        for (i, next) in self.path.iter().enumerate() {
+            if (self.old_count >> i) & 0x01 == 1 {
+                current = hash_concat(next, current);
+            } else {
+                current = hash_concat(current, ZERO_HASHES[i]);
+            }
+        }
+        current
+    }
+
+    /// Calculate the root of the new tree
+    pub fn new_root(&self) -> H256 {
+        crate::merkle_root_from_branch(self.leaf, &self.path, N, self.old_count)
+    }
+
+    /// Calculate the root of the new tree from the last leaf, zero if a leaf
+    /// after it is not empty
+    pub fn last_root(&self) -> H256 {
+        if self.new_count == 0 {
+            return H256::zero();
+        }
+        let index = self.new_count - 1;
+        if !self.path_is_last(index) {
+            return H256::zero();
+        }
+        crate::merkle_root_from_branch(self.last, &self.last_path, N, index)
+    }
+
+    // right siblings of the last leaf cover no leaves
+    fn path_is_last(&self, index: usize) -> bool {
+        self.last_path
+            .iter()
+            .enumerate()
+            .all(|(i, node)| (index >> i) & 0x01 == 1 || *node == ZERO_HASHES[i])
+    }
+
+    /// Verify the proof against the roots of the old and the new tree
+    pub fn verify(&self, old_root: H256, new_root: H256) -> Result<(), VerifyingError> {
+        if self.old_count > self.new_count
+            || (N < usize::BITS as usize && self.new_count > 1 << N)
+        {
+            return Err(VerifyingError::MalformedProof);
+        }
+        // leaves are hashes, the first new leaf is zero only if nothing was appended
+        if (self.old_count == self.new_count) != self.leaf.is_zero() {
+            return Err(VerifyingError::MalformedProof);
+        }
+        if self.old_count == self.new_count {
+            return if old_root == new_root {
+                Ok(())
+            } else {
+                Err(VerifyingError::VerificationFailed {
+                    expected: new_root,
+                    actual: old_root,
+                })
+            };
+        }
+
+        let actual = self.old_root();
+        if actual != old_root {
+            return Err(VerifyingError::VerificationFailed {
+                expected: old_root,
+                actual,
+            });
+        }
+        let actual = self.new_root();
+        if actual != new_root {
+            return Err(VerifyingError::VerificationFailed {
+                expected: new_root,
+                actual,
+            });
+        }
+        if self.last.is_zero() || !self.path_is_last(self.new_count - 1) {
+            return Err(VerifyingError::MalformedProof);
+        }
+        let actual = self.last_root();
+        if actual != new_root {
+            return Err(VerifyingError::VerificationFailed {
+                expected: new_root,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Merkle, ProvingError, Tree};
+
+    fn tree(count: u64) -> Tree<32> {
+        (1..=count).map(H256::from_low_u64_be).collect()
+    }
+
+    #[test]
+    fn it_proves_every_prefix() {
+        let new = tree(21);
+        for old_count in 0..=21 {
+            let old = tree(old_count as u64);
+            let proof = new.prove_consistency(old_count).unwrap();
+            assert_eq!(proof.old_root(), old.root());
+            assert_eq!(proof.new_root(), new.root());
+            assert_eq!(proof.last_root(), new.root());
+            proof.verify(old.root(), new.root()).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_proves_from_the_empty_tree() {
+        let new = tree(5);
+        let proof = new.prove_consistency(0).unwrap();
+        proof.verify(Tree::<32>::initial_root(), new.root()).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_a_forked_tree() {
+        let old = tree(6);
+        let mut leaves: Vec<_> = (1..=10).map(H256::from_low_u64_be).collect();
+        leaves[2] = H256::repeat_byte(0xff);
+        let forked: Tree<32> = leaves.into_iter().collect();
+
+        let proof = forked.prove_consistency(6).unwrap();
+        assert!(matches!(
+            proof.verify(old.root(), forked.root()),
+            Err(VerifyingError::VerificationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn it_rejects_bad_proofs() {
+        let old = tree(3);
+        let new = tree(9);
+        let proof = new.prove_consistency(3).unwrap();
+
+        let mut wrong_count = proof;
+        wrong_count.old_count = 4;
+        assert!(wrong_count.verify(old.root(), new.root()).is_err());
+
+        let mut shrinking = proof;
+        shrinking.new_count = 2;
+        assert!(matches!(
+            shrinking.verify(old.root(), new.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+
+        let mut zero_leaf = proof;
+        zero_leaf.leaf = H256::zero();
+        assert!(matches!(
+            zero_leaf.verify(old.root(), new.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+
+        let mut wrong_node = proof;
+        wrong_node.path[1] = H256::repeat_byte(1);
+        assert!(wrong_node.verify(old.root(), new.root()).is_err());
+
+        let mut wrong_last = proof;
+        wrong_last.last_path[0] = H256::repeat_byte(1);
+        assert!(wrong_last.verify(old.root(), new.root()).is_err());
+    }
+
+    #[test]
+    fn it_rejects_a_wrong_new_count() {
+        let old = tree(3);
+        let new = tree(9);
+        let proof = new.prove_consistency(3).unwrap();
+        for new_count in [4, 8, 10, 16, 1 << 20] {
+            let mut wrong = proof;
+            wrong.new_count = new_count;
+            assert!(wrong.verify(old.root(), new.root()).is_err(), "new_count {}", new_count);
+        }
+
+        // the branch of an earlier leaf does not prove a shorter tree
+        let mut shorter = proof;
+        let inner = new.prove(5).unwrap();
+        shorter.new_count = 6;
+        shorter.last = inner.leaf;
+        shorter.last_path = inner.path;
+        assert!(matches!(
+            shorter.verify(old.root(), new.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+
+        // nor an empty leaf past the end a longer one
+        let mut longer = proof;
+        longer.new_count = 10;
+        longer.last = H256::zero();
+        longer.last_path = new.prove(8).unwrap().path;
+        longer.last_path[0] = H256::from_low_u64_be(9);
+        assert!(matches!(
+            longer.verify(old.root(), new.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+    }
+
+    #[test]
+    fn it_rejects_a_count_above_the_tree() {
+        let tree = tree(4);
+        assert!(matches!(
+            tree.prove_consistency(5),
+            Err(ProvingError::ZeroProof { index: 5, count: 4 })
+        ));
+    }
+}
@@ -16,6 +16,9 @@ pub enum ProvingError {
         /// The number of leaves
         count: usize,
     },
+    /// Requested a multiproof of no leaves
+    #[error("Requested a multiproof of no leaves")]
+    NoLeaves,
 }
 
 /// Tree Errors
@@ -30,6 +33,9 @@ pub enum VerifyingError {
         /// The root produced by branch evaluation
         actual: H256,
     },
+    /// Proof can not be evaluated
+    #[error("Malformed proof")]
+    MalformedProof,
 }
 
 /// Error type for merkle tree ops.
@@ -2,7 +2,10 @@
 use ic_web3::types::H256;
 use once_cell::sync::Lazy;
 
-use crate::{error::IngestionError, hash_concat, EMPTY_SLICE, TREE_DEPTH, ZERO_HASHES};
+use crate::{
+    error::IngestionError, hash_concat, multiproof::walk_multiproof, EMPTY_SLICE, TREE_DEPTH,
+    ZERO_HASHES,
+};
 
 // Some code has been derived from
 // https://github.com/sigp/lighthouse/blob/c6baa0eed131c5e8ecc5860778ffc7d4a4c18d2d/consensus/merkle_proof/src/lib.rs#L25
@@ -175,6 +178,45 @@ impl MerkleTree {
 
         (current_node.hash(), proof)
     }
+
+    /// Return the hash of the node at `index` of a level, level 0 being the
+    /// leaves.
+    pub fn node_hash(&self, level: usize, index: usize, depth: usize) -> H256 {
+        let mut current_node = self;
+        let mut current_depth = depth;
+        while current_depth > level {
+            if let MerkleTree::Zero(_) = current_node {
+                return ZERO_HASHES[level];
+            }
+            let ith_bit = (index >> (current_depth - level - 1)) & 0x01;
+            // Note: unwrap is safe because leaves are only ever constructed at depth == 0.
+            let (left, right) = current_node.left_and_right_branches().unwrap();
+            current_node = if ith_bit == 1 { right } else { left };
+            current_depth -= 1;
+        }
+        current_node.hash()
+    }
+
+    /// Return the leaves at `indices` and the sibling nodes of a multiproof of
+    /// their inclusion.
+    ///
+    /// `indices` must be sorted and deduplicated. The nodes are in "bottom-up"
+    /// order, see [`MultiProof`](crate::MultiProof).
+    pub fn generate_multiproof(&self, indices: &[usize], depth: usize) -> (Vec<(usize, H256)>, Vec<H256>) {
+        let leaves: Vec<_> = indices
+            .iter()
+            .map(|&index| (index, self.node_hash(0, index, depth)))
+            .collect();
+        let mut nodes = vec![];
+        let root = walk_multiproof(&leaves, depth, |level, index| {
+            let node = self.node_hash(level, index, depth);
+            nodes.push(node);
+            Some(node)
+        });
+        debug_assert_eq!(root, Some(self.hash()));
+
+        (leaves, nodes)
+    }
 }
 
 /// Compute a root hash from a leaf and a Merkle proof.
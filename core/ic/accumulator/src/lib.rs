@@ -17,6 +17,10 @@ pub mod error;
 pub mod light;
 /// Merkle Proof struct
 pub mod proof;
+/// Merkle proof of several leaves
+pub mod multiproof;
+/// Proof that a tree extends an older one
+pub mod consistency;
 
 /// A full incremental merkle tree. Suitable for proving.
 pub mod tree;
@@ -41,7 +45,9 @@ const EMPTY_SLICE: &[H256] = &[];
 
 pub use error::*;
 pub use full::*;
+pub use consistency::*;
 pub use light::*;
+pub use multiproof::*;
 pub use proof::*;
 pub use tree::*;
 
@@ -0,0 +1,205 @@
+use crate::{hash_concat, MerkleProof, VerifyingError};
+// use ethers::prelude::H256;
+use ic_web3::types::H256;
+
+/// A proof of inclusion of several leaves against one root.
+///
+/// Siblings shared by the leaves' paths, or computable from the leaves
+/// themselves, are only included once. `nodes` holds the remaining siblings
+/// level by level, bottom-up, in increasing index order within a level.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MultiProof<const N: usize> {
+    /// The leaves with their indices, sorted by index
+    pub leaves: Vec<(usize, H256)>,
+    /// The deduplicated sibling nodes
+    pub nodes: Vec<H256>,
+}
+
+/// Walk a multiproof bottom-up. `sibling` is called with the level and index
+/// of every sibling that can not be computed from the leaves, in the order
+/// they are stored in `MultiProof::nodes`.
+pub(crate) fn walk_multiproof<F>(
+    leaves: &[(usize, H256)],
+    depth: usize,
+    mut sibling: F,
+) -> Option<H256>
+where
+    F: FnMut(usize, usize) -> Option<H256>,
+{
+    if leaves.is_empty() {
+        return None;
+    }
+    // indices must be strictly increasing and fit in the tree
+    if leaves.windows(2).any(|w| w[0].0 >= w[1].0) {
+        return None;
+    }
+    let last = leaves[leaves.len() - 1].0;
+    if depth < usize::BITS as usize && last >> depth != 0 {
+        return None;
+    }
+
+    let mut layer = leaves.to_vec();
+    for level in 0..depth {
+        let mut next = Vec::with_capacity(layer.len());
+        let mut i = 0;
+        while i < layer.len() {
+            let (index, node) = layer[i];
+            let (left, right) = if index & 1 == 0 {
+                if i + 1 < layer.len() && layer[i + 1].0 == index + 1 {
+                    i += 1;
+                    (node, layer[i].1)
+                } else {
+                    (node, sibling(level, index + 1)?)
+                }
+            } else {
+                (sibling(level, index - 1)?, node)
+            };
+            next.push((index >> 1, hash_concat(left, right)));
+            i += 1;
+        }
+        layer = next;
+    }
+
+    debug_assert_eq!(layer.len(), 1);
+    Some(layer[0].1)
+}
+
+impl<const N: usize> MultiProof<N> {
+    /// The indices of the proven leaves
+    pub fn indices(&self) -> Vec<usize> {
+        self.leaves.iter().map(|(index, _)| *index).collect()
+    }
+
+    /// Calculate the root, fails if the proof is malformed: no leaves,
+    /// unsorted or out of range indices, or a wrong number of nodes
+    pub fn try_root(&self) -> Result<H256, VerifyingError> {
+        let mut nodes = self.nodes.iter();
+        let root = walk_multiproof(&self.leaves, N, |_, _| nodes.next().copied())
+            .ok_or(VerifyingError::MalformedProof)?;
+        if nodes.next().is_some() {
+            return Err(VerifyingError::MalformedProof);
+        }
+        Ok(root)
+    }
+
+    /// Verify the proof against a root
+    pub fn verify(&self, root: H256) -> Result<(), VerifyingError> {
+        let actual = self.try_root()?;
+        if actual == root {
+            Ok(())
+        } else {
+            Err(VerifyingError::VerificationFailed {
+                expected: root,
+                actual,
+            })
+        }
+    }
+}
+
+impl<const N: usize> MerkleProof for MultiProof<N> {
+    /// Calculate the merkle root produced by evaluating the proof, zero if
+    /// the proof is malformed
+    fn root(&self) -> H256 {
+        self.try_root().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Merkle, ProvingError, Tree};
+
+    fn tree(count: u64) -> Tree<32> {
+        (0..count).map(H256::from_low_u64_be).collect()
+    }
+
+    #[test]
+    fn it_matches_single_proofs() {
+        let tree = tree(37);
+        for i in 0..37 {
+            let single = tree.prove(i).unwrap();
+            let multi = tree.prove_multi(&[i]).unwrap();
+            assert_eq!(multi.nodes.as_slice(), single.path.as_ref());
+            assert_eq!(multi.root(), tree.root());
+        }
+    }
+
+    #[test]
+    fn it_verifies_many_leaves() {
+        let tree = tree(64);
+        let indices = [0, 1, 5, 17, 18, 40, 63];
+        let proof = tree.prove_multi(&indices).unwrap();
+        assert_eq!(proof.indices(), indices);
+        proof.verify(tree.root()).unwrap();
+
+        // shared siblings are only included once
+        assert!(proof.nodes.len() < indices.len() * 32);
+    }
+
+    #[test]
+    fn it_sorts_and_dedups_indices() {
+        let tree = tree(10);
+        let proof = tree.prove_multi(&[7, 2, 7, 0]).unwrap();
+        assert_eq!(proof.indices(), [0, 2, 7]);
+        proof.verify(tree.root()).unwrap();
+    }
+
+    #[test]
+    fn it_proves_a_full_subtree() {
+        let tree = tree(8);
+        let indices: Vec<_> = (0..8).collect();
+        let proof = tree.prove_multi(&indices).unwrap();
+        // siblings above the subtree are all zero hashes
+        assert_eq!(proof.nodes.len(), 32 - 3);
+        proof.verify(tree.root()).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_bad_proofs() {
+        let tree = tree(16);
+        let proof = tree.prove_multi(&[3, 9]).unwrap();
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaves[0].1 = H256::repeat_byte(1);
+        assert!(matches!(
+            wrong_leaf.verify(tree.root()),
+            Err(VerifyingError::VerificationFailed { .. })
+        ));
+
+        let mut wrong_index = proof.clone();
+        wrong_index.leaves[1].0 = 10;
+        assert!(wrong_index.verify(tree.root()).is_err());
+
+        let mut extra_node = proof.clone();
+        extra_node.nodes.push(H256::zero());
+        assert!(matches!(
+            extra_node.verify(tree.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+
+        let mut missing_node = proof.clone();
+        missing_node.nodes.pop();
+        assert!(matches!(
+            missing_node.verify(tree.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+
+        let mut unsorted = proof;
+        unsorted.leaves.reverse();
+        assert!(matches!(
+            unsorted.verify(tree.root()),
+            Err(VerifyingError::MalformedProof)
+        ));
+    }
+
+    #[test]
+    fn it_rejects_zero_leaves() {
+        let tree = tree(4);
+        assert!(matches!(
+            tree.prove_multi(&[1, 4]),
+            Err(ProvingError::ZeroProof { index: 4, count: 4 })
+        ));
+        assert!(matches!(tree.prove_multi(&[]), Err(ProvingError::NoLeaves)));
+    }
+}
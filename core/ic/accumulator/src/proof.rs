@@ -16,7 +16,7 @@ pub struct Proof<const N: usize> {
     pub path: [H256; N],
 }
 
-mod const_array_serde {
+pub(crate) mod const_array_serde {
     use super::H256;
     use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
 
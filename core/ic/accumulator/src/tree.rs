@@ -1,4 +1,7 @@
-use crate::{full::MerkleTree, IngestionError, LightMerkle, Merkle, Proof, ProvingError};
+use crate::{
+    full::MerkleTree, ConsistencyProof, IngestionError, LightMerkle, Merkle, MultiProof, Proof,
+    ProvingError,
+};
 // use ethers::{core::types::H256, prelude::U256};
 use ic_web3::types::{H256, U256};
 
@@ -76,6 +79,67 @@ impl<const N: usize> Tree<N> {
         path.copy_from_slice(&nodes[..N]);
         Ok(Proof { leaf, index, path })
     }
+
+    /// Return the leaves at `indices` and a multiproof of their inclusion.
+    ///
+    /// Indices are sorted and deduplicated, each must be a non-zero leaf.
+    pub fn prove_multi(&self, indices: &[usize]) -> Result<MultiProof<N>, ProvingError> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        let last = *indices.last().ok_or(ProvingError::NoLeaves)?;
+
+        if last > 2usize.pow(N.try_into().unwrap()) - 1 {
+            return Err(ProvingError::IndexTooHigh(last));
+        }
+
+        let count = self.count();
+        if last >= count {
+            return Err(ProvingError::ZeroProof { index: last, count });
+        }
+
+        let (leaves, nodes) = self.tree.generate_multiproof(&indices, N);
+        Ok(MultiProof { leaves, nodes })
+    }
+
+    /// Return a proof that the tree of the first `old_count` leaves is a
+    /// prefix of this tree.
+    pub fn prove_consistency(&self, old_count: usize) -> Result<ConsistencyProof<N>, ProvingError> {
+        let count = self.count();
+        if old_count > count {
+            return Err(ProvingError::ZeroProof {
+                index: old_count,
+                count,
+            });
+        }
+
+        if old_count > 2usize.pow(N.try_into().unwrap()) - 1 {
+            return Err(ProvingError::IndexTooHigh(old_count));
+        }
+
+        let leaf = self.tree.node_hash(0, old_count, N);
+        let mut path = [H256::default(); N];
+        for (level, node) in path.iter_mut().enumerate() {
+            *node = self.tree.node_hash(level, (old_count >> level) ^ 1, N);
+        }
+        // the branch of the last leaf binds the count to the root
+        let mut last = H256::zero();
+        let mut last_path = [H256::default(); N];
+        if count > 0 {
+            last = self.tree.node_hash(0, count - 1, N);
+            for (level, node) in last_path.iter_mut().enumerate() {
+                *node = self.tree.node_hash(level, ((count - 1) >> level) ^ 1, N);
+            }
+        }
+        Ok(ConsistencyProof {
+            old_count,
+            new_count: count,
+            leaf,
+            path,
+            last,
+            last_path,
+        })
+    }
 }
 
 impl<T, const N: usize> From<T> for Tree<N>
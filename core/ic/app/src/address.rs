@@ -0,0 +1,166 @@
+use candid::Principal;
+
+// senders and recipients are bytes32 in messages, evm addresses are left padded with 12 zero bytes,
+// principals are left padded with zeros after a length byte: [len, 0.., principal bytes]
+
+/// max length of a principal
+pub const PRINCIPAL_MAX_LEN: usize = 29;
+// principals recovered from bytes32 without a length byte
+const LEGACY_PRINCIPAL_LEN: usize = 10;
+
+/// left pad a 20 bytes evm address to bytes32
+pub fn evm_address_to_bytes32(addr: &[u8; 20]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out[12..].copy_from_slice(addr);
+    out
+}
+
+/// take the evm address from bytes32, fails if it is not a padded address
+pub fn bytes32_to_evm_address(data: &[u8]) -> Result<[u8; 20], String> {
+    if data.len() != 32 {
+        return Err(format!("invalid bytes32 length: {}", data.len()));
+    }
+    if data[..12].iter().any(|b| *b != 0) {
+        return Err("not an evm address".into());
+    }
+    let mut addr = [0u8; 20];
+    addr.copy_from_slice(&data[12..]);
+    Ok(addr)
+}
+
+/// parse a hex evm address, with or without 0x
+pub fn parse_evm_address(s: &str) -> Result<[u8; 20], String> {
+    let bytes = hex::decode(s.trim_start_matches("0x"))
+        .map_err(|e| format!("invalid hex address: {:?}", e))?;
+    if bytes.len() != 20 {
+        return Err(format!("invalid evm address length: {}", bytes.len()));
+    }
+    let mut addr = [0u8; 20];
+    addr.copy_from_slice(&bytes);
+    Ok(addr)
+}
+
+/// lowercase 0x prefixed hex
+pub fn evm_address_to_hex(addr: &[u8; 20]) -> String {
+    format!("0x{}", hex::encode(addr))
+}
+
+/// bytes32 from a 20 bytes address or bytes32
+pub fn to_bytes32(data: &[u8]) -> Result<[u8; 32], String> {
+    match data.len() {
+        32 => {
+            let mut out = [0u8; 32];
+            out.copy_from_slice(data);
+            Ok(out)
+        }
+        20 => {
+            let mut addr = [0u8; 20];
+            addr.copy_from_slice(data);
+            Ok(evm_address_to_bytes32(&addr))
+        }
+        n => Err(format!("invalid address length: {}", n)),
+    }
+}
+
+/// bytes32 of a principal, [len, 0.., principal bytes]
+pub fn principal_to_bytes32(principal: &Principal) -> [u8; 32] {
+    let bytes = principal.as_slice();
+    let mut out = [0u8; 32];
+    out[0] = bytes.len() as u8;
+    out[32 - bytes.len()..].copy_from_slice(bytes);
+    out
+}
+
+/// take the principal from bytes32. without a length byte it is decoded as a left padded
+/// 10 bytes canister id, the encoding used before the length byte
+pub fn bytes32_to_principal(data: &[u8]) -> Result<Principal, String> {
+    if data.len() != 32 {
+        return Err(format!("invalid bytes32 length: {}", data.len()));
+    }
+    let len = match data[0] as usize {
+        0 => LEGACY_PRINCIPAL_LEN,
+        n if n > PRINCIPAL_MAX_LEN => return Err(format!("invalid principal length: {}", n)),
+        n => n,
+    };
+    if data[1..32 - len].iter().any(|b| *b != 0) {
+        return Err("not a principal".into());
+    }
+    Principal::try_from_slice(&data[32 - len..])
+        .map_err(|e| format!("invalid principal: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_converts_evm_addresses() {
+        let addr = parse_evm_address("0x5FbDB2315678afecb367f032d93F642f64180aa3").unwrap();
+        assert_eq!(evm_address_to_hex(&addr), "0x5fbdb2315678afecb367f032d93f642f64180aa3");
+        assert_eq!(parse_evm_address("5fbdb2315678afecb367f032d93f642f64180aa3"), Ok(addr));
+
+        let padded = evm_address_to_bytes32(&addr);
+        assert_eq!(&padded[..12], &[0u8; 12]);
+        assert_eq!(bytes32_to_evm_address(&padded), Ok(addr));
+        assert_eq!(to_bytes32(&addr), Ok(padded));
+        assert_eq!(to_bytes32(&padded), Ok(padded));
+    }
+
+    #[test]
+    fn it_round_trips_principals() {
+        let canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let bytes = principal_to_bytes32(&canister);
+        assert_eq!(bytes[0], 10);
+        assert_eq!(bytes32_to_principal(&bytes), Ok(canister));
+
+        // self-authenticating user principals are 29 bytes, the longest that fit
+        let user = Principal::self_authenticating([7u8; 44]);
+        assert_eq!(user.as_slice().len(), PRINCIPAL_MAX_LEN);
+        let bytes = principal_to_bytes32(&user);
+        assert_eq!(bytes[0], 29);
+        assert_eq!(&bytes[1..3], &[0, 0]);
+        assert_eq!(bytes32_to_principal(&bytes), Ok(user));
+
+        let anonymous = Principal::anonymous();
+        assert_eq!(bytes32_to_principal(&principal_to_bytes32(&anonymous)), Ok(anonymous));
+    }
+
+    #[test]
+    fn it_decodes_legacy_recipients() {
+        // canister ids left padded without a length byte
+        let canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let mut bytes = [0u8; 32];
+        bytes[22..].copy_from_slice(canister.as_slice());
+        assert_eq!(bytes32_to_principal(&bytes), Ok(canister));
+    }
+
+    #[test]
+    fn it_rejects_invalid_principals() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 30;
+        bytes[31] = 1;
+        assert!(bytes32_to_principal(&bytes).unwrap_err().contains("invalid principal length"));
+        bytes[0] = 0xff;
+        assert!(bytes32_to_principal(&bytes).is_err());
+
+        // the padding must be zero
+        let mut bytes = principal_to_bytes32(&Principal::anonymous());
+        bytes[5] = 1;
+        assert_eq!(bytes32_to_principal(&bytes), Err("not a principal".to_string()));
+        // an evm address is not a legacy principal
+        let addr = evm_address_to_bytes32(&[0x11; 20]);
+        assert!(bytes32_to_principal(&addr).is_err());
+        assert!(bytes32_to_principal(&[0u8; 31]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_evm_addresses() {
+        assert!(parse_evm_address("0x5fbdb2315678afecb367f032d93f642f64180a").is_err());
+        assert!(parse_evm_address("0xzz").is_err());
+        let mut padded = [0u8; 32];
+        padded[0] = 1;
+        assert!(bytes32_to_evm_address(&padded).is_err());
+        assert!(bytes32_to_evm_address(&padded[1..]).is_err());
+        assert!(to_bytes32(&[0u8; 21]).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::address::to_bytes32;
+use crate::OmnicMessage;
+
+/// checks that a message comes from the omnic proxy and a trusted sender on its origin chain,
+/// keep it in the app's state and call `authenticate` first in `handle_message`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Authenticator {
+    pub proxy: Principal,
+    pub trusted: HashMap<u32, Vec<Vec<u8>>>, // origin chain id => bytes32 senders
+}
+
+impl Authenticator {
+    pub fn new(proxy: Principal) -> Self {
+        Authenticator {
+            proxy,
+            trusted: HashMap::new(),
+        }
+    }
+
+    pub fn set_proxy(&mut self, proxy: Principal) {
+        self.proxy = proxy;
+    }
+
+    /// trust `sender` on `origin`, a 20 bytes evm address or bytes32
+    pub fn trust(&mut self, origin: u32, sender: &[u8]) -> Result<(), String> {
+        let sender = to_bytes32(sender)?.to_vec();
+        let senders = self.trusted.entry(origin).or_default();
+        if !senders.contains(&sender) {
+            senders.push(sender);
+        }
+        Ok(())
+    }
+
+    pub fn untrust(&mut self, origin: u32, sender: &[u8]) -> Result<bool, String> {
+        let sender = to_bytes32(sender)?.to_vec();
+        let senders = match self.trusted.get_mut(&origin) {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        let len = senders.len();
+        senders.retain(|s| *s != sender);
+        Ok(senders.len() != len)
+    }
+
+    pub fn is_trusted(&self, origin: u32, sender: &[u8]) -> bool {
+        match (self.trusted.get(&origin), to_bytes32(sender)) {
+            (Some(senders), Ok(sender)) => senders.iter().any(|s| s.as_slice() == sender),
+            _ => false,
+        }
+    }
+
+    pub fn check_caller(&self, caller: Principal) -> Result<(), String> {
+        if caller != self.proxy {
+            return Err(format!("caller {} is not the omnic proxy", caller));
+        }
+        Ok(())
+    }
+
+    /// check the caller of the current call and the message sender
+    pub fn authenticate(&self, message: &OmnicMessage) -> Result<(), String> {
+        self.verify(ic_cdk::caller(), message)
+    }
+
+    /// check that `caller` is the proxy and the message sender is trusted
+    pub fn verify(&self, caller: Principal, message: &OmnicMessage) -> Result<(), String> {
+        self.check_caller(caller)?;
+        if !self.is_trusted(message.origin, &message.sender) {
+            return Err(format!("sender {} on chain {} is not trusted", hex::encode(&message.sender), message.origin));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{evm_address_to_bytes32, principal_to_bytes32};
+    use crate::INTERFACE_VERSION;
+
+    const SENDER: [u8; 20] = [0x11; 20];
+
+    fn proxy() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn message(origin: u32, sender: Vec<u8>) -> OmnicMessage {
+        OmnicMessage {
+            version: INTERFACE_VERSION,
+            msg_hash: vec![0; 32],
+            origin,
+            sender,
+            nonce: 0,
+            body: vec![],
+        }
+    }
+
+    fn auth() -> Authenticator {
+        let mut auth = Authenticator::new(proxy());
+        auth.trust(5, &SENDER).unwrap();
+        auth
+    }
+
+    #[test]
+    fn it_accepts_trusted_senders_from_the_proxy() {
+        let auth = auth();
+        let sender = evm_address_to_bytes32(&SENDER).to_vec();
+        assert!(auth.verify(proxy(), &message(5, sender)).is_ok());
+        // the address itself is accepted too
+        assert!(auth.is_trusted(5, &SENDER));
+    }
+
+    #[test]
+    fn it_rejects_other_callers() {
+        let auth = auth();
+        let sender = evm_address_to_bytes32(&SENDER).to_vec();
+        for caller in [Principal::anonymous(), Principal::management_canister()] {
+            let e = auth.verify(caller, &message(5, sender.clone())).unwrap_err();
+            assert!(e.contains("is not the omnic proxy"));
+        }
+    }
+
+    #[test]
+    fn it_rejects_untrusted_senders() {
+        let mut auth = auth();
+        let sender = evm_address_to_bytes32(&SENDER).to_vec();
+        // trusted on another origin only
+        assert!(auth.verify(proxy(), &message(1, sender.clone())).is_err());
+        let other = evm_address_to_bytes32(&[0x22; 20]).to_vec();
+        assert!(auth.verify(proxy(), &message(5, other)).is_err());
+        // an ic sender with the same low bytes is not the evm sender
+        let canister = principal_to_bytes32(&proxy()).to_vec();
+        assert!(auth.verify(proxy(), &message(5, canister)).is_err());
+        assert!(auth.verify(proxy(), &message(5, vec![0x11; 7])).is_err());
+
+        assert_eq!(auth.untrust(5, &sender), Ok(true));
+        assert_eq!(auth.untrust(5, &sender), Ok(false));
+        assert!(auth.verify(proxy(), &message(5, sender)).unwrap_err().contains("is not trusted"));
+    }
+}
@@ -0,0 +1,125 @@
+/*
+omnic app sdk: shared types between the omnic proxy canister and the app canisters receiving messages
+
+    use omnic_app::{MessageHandler, OmnicMessage};
+
+    struct App;
+
+    impl MessageHandler for App {
+        fn handle_message(message: OmnicMessage) -> Result<String, String> {
+            // AUTH: thread_local Authenticator of the app, trusted senders set by the app's owner
+            AUTH.with(|a| a.borrow().authenticate(&message))?;
+            Ok("ok".into())
+        }
+    }
+
+    omnic_app::export_message_handler!(App);
+*/
+
+use candid::{CandidType, Deserialize};
+
+pub mod address;
+pub mod auth;
+
+pub use auth::Authenticator;
+pub use candid;
+pub use ic_cdk_macros;
+
+/// bump this when the handle_message interface changes,
+/// the proxy refuses to register apps built against another version
+pub const INTERFACE_VERSION: u32 = 1;
+
+/// a verified crosschain message delivered to an app canister
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OmnicMessage {
+    pub version: u32,      // INTERFACE_VERSION of the proxy
+    pub msg_hash: Vec<u8>, // leaf of the message in the origin chain's merkle tree
+    pub origin: u32,       // origin chain id
+    pub sender: Vec<u8>,   // bytes32 sender on the origin chain
+    pub nonce: u32,
+    pub body: Vec<u8>,
+}
+
+pub type HandleResult = Result<String, String>;
+
+/// implemented by app canisters, called by the proxy for each message to this canister
+pub trait MessageHandler {
+    fn handle_message(message: OmnicMessage) -> HandleResult;
+}
+
+/// check a message before handing it to the app
+pub fn check_version(message: &OmnicMessage) -> Result<(), String> {
+    if message.version != INTERFACE_VERSION {
+        return Err(format!(
+            "interface version mismatch: proxy {}, app {}",
+            message.version, INTERFACE_VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// export the `handle_message` update and the `omnic_interface_version` query of an app canister,
+/// the app must depend on ic-cdk and candid
+#[macro_export]
+macro_rules! export_message_handler {
+    ($handler:ty) => {
+        #[$crate::ic_cdk_macros::update(name = "handle_message")]
+        #[$crate::candid::candid_method(update, rename = "handle_message")]
+        fn __omnic_handle_message(message: $crate::OmnicMessage) -> $crate::HandleResult {
+            $crate::check_version(&message)?;
+            <$handler as $crate::MessageHandler>::handle_message(message)
+        }
+
+        #[$crate::ic_cdk_macros::query(name = "omnic_interface_version")]
+        #[$crate::candid::candid_method(query, rename = "omnic_interface_version")]
+        fn __omnic_interface_version() -> u32 {
+            $crate::INTERFACE_VERSION
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl MessageHandler for Echo {
+        fn handle_message(message: OmnicMessage) -> HandleResult {
+            if message.body.is_empty() {
+                return Err("empty body".into());
+            }
+            Ok(format!("{}:{}:{}", message.origin, message.nonce, hex::encode(&message.body)))
+        }
+    }
+
+    export_message_handler!(Echo);
+
+    fn message(version: u32) -> OmnicMessage {
+        OmnicMessage {
+            version,
+            msg_hash: vec![0; 32],
+            origin: 5,
+            sender: vec![0; 32],
+            nonce: 7,
+            body: vec![0xab],
+        }
+    }
+
+    #[test]
+    fn it_dispatches_to_the_handler() {
+        assert_eq!(__omnic_handle_message(message(INTERFACE_VERSION)), Ok("5:7:ab".to_string()));
+        let mut empty = message(INTERFACE_VERSION);
+        empty.body.clear();
+        assert_eq!(__omnic_handle_message(empty), Err("empty body".to_string()));
+        assert_eq!(__omnic_interface_version(), INTERFACE_VERSION);
+    }
+
+    #[test]
+    fn it_rejects_other_versions() {
+        for version in [0, INTERFACE_VERSION + 1] {
+            let res = __omnic_handle_message(message(version));
+            assert!(res.unwrap_err().contains("interface version mismatch"));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use ic_web3::types::H256;
+use ic_web3::ethabi::{decode, encode, ParamType, Token};
+
+use crate::types::{Message, MESSAGE_V0};
+use crate::OmnicError;
+use crate::OmnicError::DecodeError;
+
+/// origin of messages sent by the proxy itself
+pub const IC_CHAIN_ID: u32 = 0;
+
+/// result of delivering a message to an ic app, sent back to the message sender as
+/// abi.encode(bytes32 msgHash, bool success, bytes result), see Types.decodeAck
+#[derive(Debug, Clone)]
+pub struct Ack {
+    pub msg_hash: H256,
+    pub success: bool,
+    pub result: Vec<u8>, // Ok or Err text of handle_message
+}
+
+impl Ack {
+    pub fn new(msg_hash: H256, res: &Result<String, String>) -> Self {
+        let (success, result) = match res {
+            Ok(r) => (true, r.clone()),
+            Err(e) => (false, e.clone()),
+        };
+        Ack {
+            msg_hash,
+            success,
+            result: result.into_bytes(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode(&[
+            Token::FixedBytes(self.msg_hash.as_bytes().to_vec()),
+            Token::Bool(self.success),
+            Token::Bytes(self.result.clone()),
+        ])
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, OmnicError> {
+        let res = decode(&[ParamType::FixedBytes(32), ParamType::Bool, ParamType::Bytes], data)?;
+        let msg_hash = res[0].clone().into_fixed_bytes().ok_or(DecodeError("get msg hash failed".into()))?;
+        let success = res[1].clone().into_bool().ok_or(DecodeError("get success failed".into()))?;
+        let result = res[2].clone().into_bytes().ok_or(DecodeError("get result failed".into()))?;
+        Ok(Ack {
+            msg_hash: H256::from_slice(&msg_hash),
+            success,
+            result,
+        })
+    }
+
+    /// reply message from `app` (bytes32) to the sender of `m`
+    pub fn to_message(&self, m: &Message, app: H256, nonce: u32) -> Message {
+        Message {
+            version: MESSAGE_V0,
+            origin: IC_CHAIN_ID,
+            sender: app,
+            nonce,
+            destination: m.origin,
+            recipient: m.sender,
+            extensions: vec![],
+            body: self.encode(),
+        }
+    }
+}
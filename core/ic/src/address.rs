@@ -0,0 +1,27 @@
+use candid::Principal;
+use ic_web3::types::H256;
+
+// the codecs are shared with apps through the sdk
+pub use omnic_app::address::*;
+
+/// ic recipient of a message to destination 0
+pub fn recipient_principal(recipient: &H256) -> Result<Principal, String> {
+    bytes32_to_principal(recipient.as_bytes())
+}
+
+/// bytes32 sender of a message from an ic canister
+pub fn principal_to_h256(principal: &Principal) -> H256 {
+    H256::from(principal_to_bytes32(principal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_recovers_recipients_longer_than_canister_ids() {
+        let user = Principal::self_authenticating([7u8; 44]);
+        let recipient = principal_to_h256(&user);
+        assert_eq!(recipient_principal(&recipient), Ok(user));
+    }
+}
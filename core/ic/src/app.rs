@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use candid::{CandidType, Deserialize, Principal};
+
+use omnic_app::address::to_bytes32;
+
+use crate::ratelimit::RateLimit;
+
+/// routing permissions of an ic app, only messages matching them are delivered to it
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct AppConfig {
+    pub allowed_origins: Vec<u32>,       // empty for any origin chain
+    pub allowed_senders: Vec<Vec<u8>>,   // 20 bytes evm addresses or bytes32, empty for any sender
+    pub max_payload: u64,                // bytes, 0 for no limit
+    pub rate_limit: Option<RateLimit>,   // overrides the default per recipient limit
+    pub ack: bool,                       // reply the handle_message result to the sender
+}
+
+impl AppConfig {
+    pub fn normalized(mut self) -> Result<Self, String> {
+        self.allowed_senders = self.allowed_senders.iter()
+            .map(|s| to_bytes32(s).map(|s| s.to_vec()))
+            .collect::<Result<Vec<Vec<u8>>, String>>()?;
+        Ok(self)
+    }
+
+    pub fn check(&self, origin: u32, sender: &[u8], payload_len: usize) -> Result<(), String> {
+        if !self.allowed_origins.is_empty() && !self.allowed_origins.contains(&origin) {
+            return Err(format!("origin chain {} not allowed by app", origin));
+        }
+        if !self.allowed_senders.is_empty() && !self.allowed_senders.iter().any(|s| s.as_slice() == sender) {
+            return Err(format!("sender {} not allowed by app", hex::encode(sender)));
+        }
+        if self.max_payload > 0 && payload_len as u64 > self.max_payload {
+            return Err(format!("payload too large: {} > {}", payload_len, self.max_payload));
+        }
+        Ok(())
+    }
+}
+
+/// ic apps that opted in to filtering, apps not registered receive all messages
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct AppRegistry {
+    pub apps: HashMap<Principal, AppConfig>,
+}
+
+impl AppRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, app: Principal, config: AppConfig) -> Result<(), String> {
+        let config = config.normalized()?;
+        self.apps.insert(app, config);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, app: &Principal) -> bool {
+        self.apps.remove(app).is_some()
+    }
+
+    pub fn get(&self, app: &Principal) -> Option<AppConfig> {
+        self.apps.get(app).cloned()
+    }
+
+    /// check a message to `app` against its config
+    pub fn check(&self, app: Principal, origin: u32, sender: &[u8], payload_len: usize) -> Result<(), String> {
+        match self.apps.get(&app) {
+            Some(config) => config.check(origin, sender, payload_len),
+            None => Ok(()),
+        }
+    }
+
+    pub fn ack_enabled(&self, app: &Principal) -> bool {
+        self.apps.get(app).map(|c| c.ack).unwrap_or_default()
+    }
+
+    pub fn rate_limit(&self, app: &Principal) -> Option<RateLimit> {
+        self.apps.get(app).and_then(|c| c.rate_limit)
+    }
+}
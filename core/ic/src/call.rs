@@ -1,15 +1,25 @@
 use candid::Principal;
 use ic_cdk::api::call::{call, CallResult};
+use omnic_app::{OmnicMessage, HandleResult, INTERFACE_VERSION};
 
 use crate::consts::{MAX_RESP_BYTES, CYCLES_PER_CALL};
 use crate::types::Message;
 use crate::chains::EVMChainClient;
+use crate::secret::RpcEndpoint;
 use crate::traits::chain::HomeContract;
 
 pub async fn call_to_canister(recipient: Principal, msg_hash: Vec<u8>, m: &Message) -> Result<String, String> {
-    // call ic recipient canister
-    let ret: CallResult<(Result<String, String>,)> = 
-        call(recipient, "handle_message", (msg_hash, m.origin, m.sender.as_bytes(), m.nonce, m.body.clone(), )).await;
+    // call ic recipient canister, see omnic_app::export_message_handler
+    let message = OmnicMessage {
+        version: INTERFACE_VERSION,
+        msg_hash,
+        origin: m.origin,
+        sender: m.sender.as_bytes().to_vec(),
+        nonce: m.nonce,
+        body: m.body.clone(),
+    };
+    let ret: CallResult<(HandleResult,)> = 
+        call(recipient, "handle_message", (message, )).await;
     match ret {
         Ok((res, )) => {
             match res {
@@ -33,18 +43,32 @@ pub async fn call_to_canister(recipient: Principal, msg_hash: Vec<u8>, m: &Messa
     }
 }
 
+// interface version of an app canister, fails if it does not export omnic_interface_version
+pub async fn get_app_version(app: Principal) -> Result<u32, String> {
+    let ret: CallResult<(u32,)> = call(app, "omnic_interface_version", ()).await;
+    ret.map(|(v, )| v)
+        .map_err(|(_code, msg)| format!("get app interface version failed: {:?}", (_code, msg)))
+}
+
 pub async fn call_to_chain(
     caller: String, 
     omnic_addr: String, 
-    rpc: String, 
+    rpc: RpcEndpoint, 
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
     dst_chain: u32, 
-    msg_bytes: Vec<u8>
+    msg_bytes: Vec<u8>,
+    batch: bool,
 ) -> Result<String, String> {
-    let client = EVMChainClient::new(rpc.clone(), omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL)
-        .map_err(|e| format!("init EVMChainClient failed: {:?}", e))?;
-    client
-        .dispatch_message(caller, dst_chain, msg_bytes)
-        .await
+    let client = EVMChainClient::new(rpc, omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL)
+        .map_err(|e| format!("init EVMChainClient failed: {:?}", e))?
+        .with_signer(key_name, derivation_path);
+    let res = if batch {
+        client.dispatch_batch(caller, dst_chain, msg_bytes).await
+    } else {
+        client.dispatch_message(caller, dst_chain, msg_bytes).await
+    };
+    res
         .map(|txhash| {
             // ic_cdk::println!("dispatch_message txhash: {:?}", hex::encode(txhash));
             // true
@@ -52,3 +76,23 @@ pub async fn call_to_chain(
         })
         .map_err(|e| format!("dispatch_message failed: {:?}", e))
 }
+
+/// send `msgs` to the destination in one processMessageBatch tx
+pub async fn call_to_chain_batch(
+    caller: String, 
+    omnic_addr: String, 
+    rpc: RpcEndpoint, 
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    dst_chain: u32, 
+    msgs: Vec<Vec<u8>>,
+) -> Result<String, String> {
+    let client = EVMChainClient::new(rpc, omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL)
+        .map_err(|e| format!("init EVMChainClient failed: {:?}", e))?
+        .with_signer(key_name, derivation_path);
+    client
+        .dispatch_message_batch(caller, dst_chain, msgs)
+        .await
+        .map(|txhash| hex::encode(txhash))
+        .map_err(|e| format!("dispatch_message_batch failed: {:?}", e))
+}
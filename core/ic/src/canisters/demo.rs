@@ -1,16 +1,20 @@
 use std::str;
-use ic_cdk_macros::{query, update};
+use ic_cdk_macros::query;
 use ic_cdk::export::candid::{candid_method};
 use ic_cdk::export::Principal;
+use omnic_app::{MessageHandler, OmnicMessage, HandleResult};
 
+struct Demo;
 
-#[update(name = "handle_message")]
-#[candid_method(update, rename = "handle_message")]
-fn handle_message(origin: u32, nonce: u32, sender: Vec<u8>, body: Vec<u8>) -> Result<bool, String> {
-    ic_cdk::println!("demo app got message: {:?}", (origin, nonce, hex::encode(&sender), str::from_utf8(&body)));
-    Ok(true)
+impl MessageHandler for Demo {
+    fn handle_message(message: OmnicMessage) -> HandleResult {
+        ic_cdk::println!("demo app got message: {:?}", (message.origin, message.nonce, hex::encode(&message.sender), str::from_utf8(&message.body)));
+        Ok("demo app got message".into())
+    }
 }
 
+omnic_app::export_message_handler!(Demo);
+
 #[query(name = "hex_pid")]
 #[candid_method(query, rename = "hex_pid")]
 fn hex_pid(pid: Principal) {
@@ -22,4 +26,4 @@ fn hex_pid(pid: Principal) {
 fn main() {
     candid::export_service!();
     std::print!("{}", __export_service());
-}
\ No newline at end of file
+}
@@ -20,12 +20,13 @@ use candid::types::principal::Principal;
 
 use ic_cron::types::Iterations;
 
-use accumulator::{TREE_DEPTH, merkle_root_from_branch};
-use omnic::{Message, chains::EVMChainClient, ChainConfig, ChainState, ChainType};
-use omnic::HomeContract;
+use accumulator::{TREE_DEPTH, merkle_root_from_branch, MultiProof, ConsistencyProof};
+use omnic::{Message, chains::EVMChainClient, ChainConfig, ChainState, ChainStateV0, ChainType, PauseState};
+use omnic::{HomeContract, DetailValue, Record, RootCheckpoint};
 use omnic::consts::{MAX_RESP_BYTES, CYCLES_PER_CALL, CYCLES_PER_BYTE};
-use omnic::state::{State, StateMachine, StateMachineStable, StateInfo};
-use omnic::utils::check_roots_result;
+use omnic::state::{State, StateMachine, StateMachineStable, StateInfo, StateInfoV0, RecordDB, Role};
+use omnic::utils::{check_roots_result, DetailsBuilder};
+use omnic::secret::{RpcSecrets, RpcCredential, CredentialPlacement, RpcEndpoint, redact_url, redact_urls, redact_text};
 
 ic_cron::implement_cron!();
 
@@ -40,6 +41,9 @@ thread_local! {
     static CHAINS: RefCell<ChainState>  = RefCell::new(ChainState::default());
     static STATE_MACHINE: RefCell<StateMachine> = RefCell::new(StateMachine::default());
     static LOGS: RefCell<VecDeque<String>> = RefCell::new(VecDeque::default());
+    static RPC_SECRETS: RefCell<RpcSecrets> = RefCell::new(RpcSecrets::new());
+    static RECORDS: RefCell<RecordDB> = RefCell::new(RecordDB::new());
+    static CHECKPOINT: RefCell<RootCheckpoint> = RefCell::new(RootCheckpoint::new());
 }
 
 #[query]
@@ -75,7 +79,7 @@ fn init() {
     let caller = ic_cdk::api::caller();
     STATE_INFO.with(|info| {
         let mut info = info.borrow_mut();
-        info.add_owner(caller);
+        info.grant_role(caller, Role::Admin);
     });
 
     // set up cron job
@@ -89,7 +93,7 @@ fn init() {
     ).unwrap();
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_fetch_period")]
 async fn set_fetch_period(fetch_root_period: u64, fetch_roots_period: u64) -> Result<bool, String> {
     STATE_INFO.with(|s| {
@@ -99,7 +103,7 @@ async fn set_fetch_period(fetch_root_period: u64, fetch_roots_period: u64) -> Re
     Ok(true)
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_rpc_number")]
 async fn set_rpc_number(query_rpc_number: u64) -> Result<bool, String> {
     let rpc_url_count = CHAINS.with(|c| {
@@ -116,7 +120,7 @@ async fn set_rpc_number(query_rpc_number: u64) -> Result<bool, String> {
     Ok(true)
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "add_chain")]
 fn add_chain(
     chain_id: u32, 
@@ -146,7 +150,7 @@ fn add_chain(
     Ok(true)
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "is_operator")]
 #[candid_method(update, rename = "add_urls")]
 fn add_urls(
     urls: Vec<String>
@@ -159,8 +163,45 @@ fn add_urls(
     Ok(true)
 }
 
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "remove_url")]
+fn remove_url(
+    url: String
+) -> Result<bool, String> {
+    // keep enough urls for the rpc number of each round
+    let rpc_number = get_query_rpc_number();
+    CHAINS.with(|c| {
+        let mut c = c.borrow_mut();
+        if !c.rpc_urls().contains(&url) {
+            return Err("url not exist".to_string());
+        }
+        if (c.rpc_urls().len() - 1) < rpc_number as usize {
+            return Err(format!("need at least {} rpc urls", rpc_number));
+        }
+        c.remove_url(&url);
+        Ok(true)
+    })
+}
+
+// replace all urls, roots and next index are kept
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "replace_urls")]
+fn replace_urls(
+    urls: Vec<String>
+) -> Result<bool, String> {
+    let rpc_number = get_query_rpc_number();
+    if urls.len() < rpc_number as usize {
+        return Err(format!("need at least {} rpc urls", rpc_number));
+    }
+    CHAINS.with(|c| {
+        let mut c = c.borrow_mut();
+        c.replace_urls(urls);
+    });
+    Ok(true)
+}
+
 // set next index
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_next_index")]
 fn set_next_index(
     next_index: u32
@@ -177,11 +218,82 @@ fn set_next_index(
 fn get_chain() -> Result<ChainState, String> {
     CHAINS.with(|chain| {
         let chain = chain.borrow();
-        Ok(chain.clone())
+        Ok(chain.redacted())
+    })
+}
+
+// raw rpc urls, may contain api keys
+#[query(name = "get_rpc_urls", guard = "is_operator")]
+#[candid_method(query, rename = "get_rpc_urls")]
+fn get_rpc_urls() -> Result<Vec<String>, String> {
+    CHAINS.with(|chain| {
+        let chain = chain.borrow();
+        Ok(chain.rpc_urls())
+    })
+}
+
+// set the api key of a rpc provider url, the secret is never returned by any query
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_rpc_credential")]
+fn set_rpc_credential(url: String, placement: CredentialPlacement, secret: String) -> Result<bool, String> {
+    RPC_SECRETS.with(|s| {
+        s.borrow_mut().set_credential(url, RpcCredential { placement, secret });
+    });
+    Ok(true)
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "remove_rpc_credential")]
+fn remove_rpc_credential(url: String) -> Result<bool, String> {
+    if RPC_SECRETS.with(|s| s.borrow_mut().remove_credential(&url)) {
+        Ok(true)
+    } else {
+        Err("credential not exist".to_string())
+    }
+}
+
+// pause message verification of this chain
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "set_paused")]
+fn set_paused(paused: bool) -> Result<bool, String> {
+    CHAINS.with(|c| {
+        let mut chain = c.borrow_mut();
+        let destination_paused = chain.destination_paused;
+        chain.set_paused(paused, destination_paused);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_paused".to_string(), 
+        DetailsBuilder::new()
+            .insert("origin_paused", if paused { DetailValue::True } else { DetailValue::False })
+    );
+    Ok(true)
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_breaker_threshold")]
+fn set_breaker_threshold(threshold: u32) -> Result<bool, String> {
+    STATE_INFO.with(|s| {
+        s.borrow_mut().set_breaker_threshold(threshold);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_breaker_threshold".to_string(), 
+        DetailsBuilder::new()
+            .insert("threshold", DetailValue::U64(threshold as u64))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_pause_state")]
+#[candid_method(query, rename = "get_pause_state")]
+fn get_pause_state() -> Result<PauseState, String> {
+    CHAINS.with(|c| {
+        Ok(c.borrow().pause_state())
     })
 }
 
-#[query(name = "get_info", guard = "is_authorized")]
+#[query(name = "get_info", guard = "is_auditor")]
 #[candid_method(query, rename = "get_info")]
 fn get_info() -> Result<StateInfo, String> {
     STATE_INFO.with(|info| {
@@ -198,7 +310,7 @@ async fn fetch(height: u64) -> Result<String, String> {
         (chain.canister_addr.clone(), chain.config.omnic_addr.clone(), chain.config.rpc_urls[0].clone())
     });
 
-    let client = EVMChainClient::new(rpc, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
     client.get_latest_root(Some(height))
         .await
@@ -228,7 +340,7 @@ async fn get_tx_count(addr: String) -> Result<u64, String> {
         _ => return Err("chain type not supported yet".into()),
     }
 
-    let client = EVMChainClient::new(rpc_url, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc_url), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
 
     client.get_tx_count(addr)
@@ -258,7 +370,7 @@ async fn get_gas_price() -> Result<u64, String> {
         _ => return Err("chain type not supported yet".into()),
     }
 
-    let client = EVMChainClient::new(rpc_url, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc_url), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
 
     client.get_gas_price()
@@ -272,6 +384,10 @@ async fn get_gas_price() -> Result<u64, String> {
 fn is_valid(message: Vec<u8>, proof: Vec<Vec<u8>>, leaf_index: u32) -> Result<bool, String> {
     // verify message proof: use proof, message to calculate the merkle root, 
     // check if the merkle root exists in corresponding chain state
+    let paused = CHAINS.with(|c| c.borrow().origin_paused);
+    if paused {
+        return Err("chain is paused".into());
+    }
     let m = Message::from_raw(message.clone()).map_err(|e| {
         format!("parse message from bytes failed: {:?}", e)
     })?;
@@ -287,6 +403,126 @@ fn is_valid(message: Vec<u8>, proof: Vec<Vec<u8>>, leaf_index: u32) -> Result<bo
     })
 }
 
+// check several messages against one root, nodes are the deduplicated siblings of a multiproof
+#[query(name = "is_valid_batch")]
+#[candid_method(query, rename = "is_valid_batch")]
+fn is_valid_batch(messages: Vec<Vec<u8>>, leaf_indexes: Vec<u32>, nodes: Vec<Vec<u8>>) -> Result<bool, String> {
+    let paused = CHAINS.with(|c| c.borrow().origin_paused);
+    if paused {
+        return Err("chain is paused".into());
+    }
+    if messages.len() != leaf_indexes.len() {
+        return Err("messages and leaf indexes length mismatch".into());
+    }
+    let mut leaves = Vec::with_capacity(messages.len());
+    for (message, index) in messages.into_iter().zip(leaf_indexes) {
+        let m = Message::from_raw(message).map_err(|e| {
+            format!("parse message from bytes failed: {:?}", e)
+        })?;
+        leaves.push((index as usize, m.to_leaf()));
+    }
+    leaves.sort_by_key(|(index, _)| *index);
+    if nodes.iter().any(|n| n.len() != 32) {
+        return Err("parse proof failed: invalid node length".into());
+    }
+    let proof: MultiProof<TREE_DEPTH> = MultiProof {
+        leaves,
+        nodes: nodes.iter().map(|n| H256::from_slice(n)).collect(),
+    };
+    let root = proof.try_root().map_err(|e| format!("parse proof failed: {:?}", e))?;
+    CHAINS.with(|c| {
+        let chain = c.borrow();
+        Ok(chain.is_root_exist(root))
+    })
+}
+
+// hold fetched roots until a consistency proof shows they extend the checkpoint
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_consistency_required")]
+fn set_consistency_required(required: bool) -> Result<bool, String> {
+    CHECKPOINT.with(|c| {
+        c.borrow_mut().set_required(required);
+    });
+    add_record(
+        ic_cdk::caller(),
+        "set_consistency_required".to_string(),
+        DetailsBuilder::new()
+            .insert("required", if required { DetailValue::True } else { DetailValue::False })
+    );
+    Ok(true)
+}
+
+// trust a root with its leaf count as the start of consistency checks, pending roots are dropped
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_checkpoint")]
+fn set_checkpoint(count: u32, root: Vec<u8>) -> Result<bool, String> {
+    if root.len() != 32 {
+        return Err("invalid root length".into());
+    }
+    let root = H256::from_slice(&root);
+    CHECKPOINT.with(|c| {
+        c.borrow_mut().set_checkpoint(count, root);
+    });
+    add_record(
+        ic_cdk::caller(),
+        "set_checkpoint".to_string(),
+        DetailsBuilder::new()
+            .insert("count", DetailValue::U64(count as u64))
+            .insert("root", DetailValue::Text(format!("{:x}", root)))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_checkpoint")]
+#[candid_method(query, rename = "get_checkpoint")]
+fn get_checkpoint() -> RootCheckpoint {
+    CHECKPOINT.with(|c| c.borrow().clone())
+}
+
+fn parse_branch(leaf: &[u8], path: &[Vec<u8>]) -> Result<(H256, [H256; TREE_DEPTH]), String> {
+    if leaf.len() != 32 || path.iter().any(|n| n.len() != 32) {
+        return Err("parse proof failed: invalid hash length".into());
+    }
+    let p_h256: Vec<H256> = path.iter().map(|v| H256::from_slice(&v)).collect();
+    let path: [H256; TREE_DEPTH] = p_h256.try_into().map_err(|e| format!("parse proof failed: {:?}", e))?;
+    Ok((H256::from_slice(leaf), path))
+}
+
+// accept a pending root with a proof that the checkpoint tree is a prefix of its tree,
+// leaf and path are the branch of leaf old_count in the new tree,
+// last and last_path the branch of leaf new_count - 1, which binds new_count to the root
+#[update(guard = "is_relayer")]
+#[candid_method(update, rename = "prove_consistency")]
+fn prove_consistency(new_root: Vec<u8>, old_count: u32, new_count: u32, leaf: Vec<u8>, path: Vec<Vec<u8>>, last: Vec<u8>, last_path: Vec<Vec<u8>>) -> Result<bool, String> {
+    if new_root.len() != 32 {
+        return Err("parse proof failed: invalid hash length".into());
+    }
+    let new_root = H256::from_slice(&new_root);
+    let (leaf, path) = parse_branch(&leaf, &path)?;
+    let (last, last_path) = parse_branch(&last, &last_path)?;
+    let proof: ConsistencyProof<TREE_DEPTH> = ConsistencyProof {
+        old_count: old_count as usize,
+        new_count: new_count as usize,
+        leaf,
+        path,
+        last,
+        last_path,
+    };
+    CHECKPOINT.with(|c| c.borrow_mut().accept(&proof, new_root))?;
+    CHAINS.with(|c| {
+        c.borrow_mut().insert_root(new_root);
+    });
+    add_record(
+        ic_cdk::caller(),
+        "accept_root".to_string(),
+        DetailsBuilder::new()
+            .insert("root", DetailValue::Text(format!("{:x}", new_root)))
+            .insert("old_count", DetailValue::U64(old_count as u64))
+            .insert("new_count", DetailValue::U64(new_count as u64))
+    );
+    Ok(true)
+}
+
 #[query(name = "get_latest_root")]
 #[candid_method(query, rename = "get_latest_root")]
 fn get_latest_root() -> String {
@@ -305,20 +541,98 @@ fn get_next_index() -> Result<u32, String> {
     })
 }
 
-#[update(name = "add_owner", guard = "is_authorized")]
-#[candid_method(update, rename = "add_owner")]
-async fn add_owner(owner: Principal) {
+// origin chain block height of the latest root fetch
+#[query(name = "get_block_height")]
+#[candid_method(query, rename = "get_block_height")]
+fn get_block_height() -> u64 {
+    STATE_MACHINE.with(|s| s.borrow().block_height)
+}
+
+#[update(name = "grant_role", guard = "is_admin")]
+#[candid_method(update, rename = "grant_role")]
+fn grant_role(user: Principal, role: Role) -> Result<bool, String> {
     STATE_INFO.with(|s| {
-        s.borrow_mut().add_owner(owner);
+        s.borrow_mut().grant_role(user, role);
     });
+    add_record(
+        ic_cdk::caller(), 
+        "grant_role".to_string(), 
+        DetailsBuilder::new()
+            .insert("user", DetailValue::Principal(user))
+            .insert("role", DetailValue::Text(format!("{:?}", role)))
+    );
+    Ok(true)
 }
 
-#[update(name = "remove_owner", guard = "is_authorized")]
-#[candid_method(update, rename = "remove_owner")]
-async fn remove_owner(owner: Principal) {
+#[update(name = "revoke_role", guard = "is_admin")]
+#[candid_method(update, rename = "revoke_role")]
+fn revoke_role(user: Principal, role: Role) -> Result<bool, String> {
     STATE_INFO.with(|s| {
-        s.borrow_mut().delete_owner(owner);
-    });
+        let mut s = s.borrow_mut();
+        if role == Role::Admin && s.has_role(user, Role::Admin) && s.admin_count() == 1 {
+            return Err("can not revoke the last admin".to_string());
+        }
+        if !s.revoke_role(user, role) {
+            return Err("user does not have this role".to_string());
+        }
+        Ok(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "revoke_role".to_string(), 
+        DetailsBuilder::new()
+            .insert("user", DetailValue::Principal(user))
+            .insert("role", DetailValue::Text(format!("{:?}", role)))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_roles")]
+#[candid_method(query, rename = "get_roles")]
+fn get_roles(user: Principal) -> Vec<Role> {
+    STATE_INFO.with(|s| {
+        s.borrow().get_roles(user)
+    })
+}
+
+#[query(name = "get_record_size", guard = "is_auditor")]
+#[candid_method(query, rename = "get_record_size")]
+fn get_record_size(operation: Option<String>) -> usize {
+    RECORDS.with(|r| {
+        let records = r.borrow();
+        records.size(operation)
+    })
+}
+
+#[query(name = "get_records", guard = "is_auditor")]
+#[candid_method(query, rename = "get_records")]
+fn get_records(range: Option<(usize, usize)>, operation: Option<String>) -> Vec<Record> {
+    RECORDS.with(|r| {
+        let records = r.borrow();
+        let (start, end) = match range {
+            Some((s, e)) => {
+                (s, e)
+            }
+            None => {
+                // range not set, default to last 50 records
+                let size = records.size(operation.clone());
+                if size < 50 {
+                    (0, size)
+                } else {
+                    (size-50, size)
+                }
+            }
+        };
+
+        match operation {
+            Some(op) => {
+                records.load_by_opeation(op, start, end)
+            }
+            None => {
+                records.load_by_id_range(start, end)
+            }
+        }
+    })
 }
 
 async fn fetch_root() {
@@ -330,7 +644,7 @@ async fn fetch_root() {
     
     let next_state = match state.sub_state {
         State::Init => {
-            match EVMChainClient::new(state.rpc_urls[0].clone(), state.omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL) {
+            match EVMChainClient::new(get_endpoint(&state.rpc_urls[0]), state.omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL) {
                 Ok(client) => { 
                     match client.get_block_number().await {
                         Ok(h) => {
@@ -354,10 +668,10 @@ async fn fetch_root() {
         },
         State::Fetching(idx) => {
             // query root in block height
-            match EVMChainClient::new(state.rpc_urls[0].clone(), state.omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL) {
+            match EVMChainClient::new(get_endpoint(&state.rpc_urls[0]), state.omnic_addr.clone(), MAX_RESP_BYTES, CYCLES_PER_CALL) {
                 Ok(client) => {
                     let root = client.get_latest_root(Some(state.block_height)).await;
-                    add_log(format!("root from {:?}: {:?}", state.rpc_urls[idx], root));
+                    add_log(format!("root from {:?}: {:?}", redact_url(&state.rpc_urls[idx]), root));
                     match root {
                         Ok(r) => {
                             incr_state_root(r);
@@ -441,7 +755,7 @@ async fn fetch_roots() {
                             STATE_MACHINE.with(|s| {
                                 s.borrow_mut().set_rpc_urls(random_urls.clone());
                             });
-                            add_log(format!("start fetching, random rpc urls: {:?}", random_urls));
+                            add_log(format!("start fetching, random rpc urls: {:?}", redact_urls(&random_urls)));
                             add_log(format!("start_cycles: {:?},  start_time: {:?}", ic_cdk::api::canister_balance(), ic_cdk::api::time()));
                             cron_enqueue(
                                 Task::FetchRoot, 
@@ -462,15 +776,22 @@ async fn fetch_roots() {
                 State::End => {
                     add_log(format!("end_cycles: {:?},  end_time: {:?}", ic_cdk::api::canister_balance(), ic_cdk::api::time()));
                     // update root
-                    CHAINS.with(|c| {
+                    let check_result = CHAINS.with(|c| {
                         let mut chain = c.borrow_mut();
                         let (check_result, root) = check_roots_result(&state.roots, state.rpc_count());
                         if check_result {
-                            chain.insert_root(root);
+                            let required = CHECKPOINT.with(|c| c.borrow().required);
+                            if !required {
+                                chain.insert_root(root);
+                            } else if !chain.is_root_exist(root) && CHECKPOINT.with(|c| c.borrow_mut().add_pending(root)) {
+                                add_log(format!("root {:x} pending consistency proof", root));
+                            }
                         } else {
                             add_log(format!("invalid roots: {:?}", state.roots))
                         }
+                        check_result
                     });
+                    record_root_check(check_result);
                     // update state
                     STATE_MACHINE.with(|s| {
                         let mut state = s.borrow_mut();
@@ -478,6 +799,11 @@ async fn fetch_roots() {
                     });
                 },
                 State::Fail => {
+                    // rpc failures say nothing about the chain, only providers returning different roots count
+                    let disagree = state.roots.keys().filter(|r| !r.is_zero()).count() > 1;
+                    if disagree {
+                        record_root_check(false);
+                    }
                     // update state
                     STATE_MACHINE.with(|s| {
                         let mut state = s.borrow_mut();
@@ -516,20 +842,60 @@ fn pre_upgrade() {
     let state_machine = STATE_MACHINE.with(|s| {
         s.replace(StateMachine::default())
     });
-    ic_cdk::storage::stable_save((chains, state_info, StateMachineStable::from(state_machine), _take_cron_state())).expect("pre upgrade error");
-}
+    let rpc_secrets = RPC_SECRETS.with(|s| {
+        s.replace(RpcSecrets::new())
+    });
+    let records = RECORDS.with(|r| {
+        r.replace(RecordDB::new())
+    });
+    let checkpoint = CHECKPOINT.with(|c| {
+        c.replace(RootCheckpoint::new())
+    });
+    // post_upgrade restores this layout, or the baseline one of restore_v0
+    ic_cdk::storage::stable_save((chains, state_info, StateMachineStable::from(state_machine), _take_cron_state(), rpc_secrets, records, checkpoint)).expect("pre upgrade error");
+}
+
+// stable layout of the gateway before roles and per chain state,
+// restored when the current layout does not decode
+fn restore_v0(current_err: String) -> StableState {
+    let (chains, state_info, state_machine, cron_state): (ChainStateV0, StateInfoV0, StateMachineStable, Option<TaskScheduler>) =
+        ic_cdk::storage::stable_restore()
+            .unwrap_or_else(|e| panic!("post upgrade error: {}, baseline layout: {}", current_err, e));
+    (
+        chains.into(),
+        state_info.into(),
+        state_machine,
+        cron_state,
+        RpcSecrets::new(),
+        RecordDB::new(),
+        RootCheckpoint::new(),
+    )
+}
+
+type StableState = (
+    ChainState, 
+    StateInfo, 
+    StateMachineStable, 
+    Option<TaskScheduler>,
+    RpcSecrets,
+    RecordDB,
+    RootCheckpoint,
+);
 
 #[post_upgrade]
 fn post_upgrade() {
+    let restored: Result<StableState, String> = ic_cdk::storage::stable_restore();
     let (chains, 
         state_info, 
         state_machine, 
-        cron_state
-    ): (ChainState, 
-        StateInfo, 
-        StateMachineStable, 
-        Option<TaskScheduler>
-    ) = ic_cdk::storage::stable_restore().expect("post upgrade error");
+        cron_state,
+        rpc_secrets,
+        records,
+        checkpoint,
+    ) = match restored {
+        Ok(state) => state,
+        Err(e) => restore_v0(e),
+    };
     
     CHAINS.with(|c| {
         c.replace(chains);
@@ -541,18 +907,27 @@ fn post_upgrade() {
         s.replace(state_machine.into());
     });
     _put_cron_state(cron_state);
+    RPC_SECRETS.with(|s| {
+        s.replace(rpc_secrets);
+    });
+    RECORDS.with(|r| {
+        r.replace(records);
+    });
+    CHECKPOINT.with(|c| {
+        c.replace(checkpoint);
+    });
 }
 
 /// get the unix timestamp in second
-// fn get_time() -> u64 {
-//     ic_cdk::api::time() / 1000000000
-// }
+fn get_time() -> u64 {
+    ic_cdk::api::time() / 1000000000
+}
 
-fn is_authorized() -> Result<(), String> {
+fn check_role(role: Role) -> Result<(), String> {
     let user = ic_cdk::api::caller();
     STATE_INFO.with(|info| {
         let info = info.borrow();
-        if !info.is_owner(user) {
+        if !info.has_role(user, role) {
             Err("unauthorized!".into())
         } else {
             Ok(())
@@ -560,6 +935,46 @@ fn is_authorized() -> Result<(), String> {
     })
 }
 
+fn is_admin() -> Result<(), String> {
+    check_role(Role::Admin)
+}
+
+fn is_operator() -> Result<(), String> {
+    check_role(Role::Operator)
+}
+
+fn is_auditor() -> Result<(), String> {
+    check_role(Role::Auditor)
+}
+
+fn is_relayer() -> Result<(), String> {
+    check_role(Role::Relayer)
+}
+
+// resolve the real endpoint of a provider url with its credential
+fn get_endpoint(url: &str) -> RpcEndpoint {
+    RPC_SECRETS.with(|s| s.borrow().resolve(url))
+}
+
+// update the root breaker, stop verifying messages when roots keep mismatching
+fn record_root_check(success: bool) {
+    let threshold = STATE_INFO.with(|s| s.borrow().breaker_threshold);
+    let (tripped, chain_id) = CHAINS.with(|c| {
+        let mut c = c.borrow_mut();
+        (c.record_root_check(success, threshold), c.config.chain_id)
+    });
+    if tripped {
+        add_log("breaker tripped, chain paused after root check failures".to_string());
+        add_record(
+            ic_cdk::id(), 
+            "breaker_tripped".to_string(), 
+            DetailsBuilder::new()
+                .insert("chain_id", DetailValue::U64(chain_id as u64))
+                .insert("reason", DetailValue::Text("root mismatches".to_string()))
+        );
+    }
+}
+
 fn incr_state_root(root: H256) {
     STATE_MACHINE.with(|s| {
         let mut state = s.borrow_mut();
@@ -571,13 +986,26 @@ fn incr_state_root(root: H256) {
     })
 }
 
+fn add_record(caller: Principal, op: String, details_builder: DetailsBuilder) {
+    RECORDS.with(|r| {
+        let mut records = r.borrow_mut();
+        records.append(
+            caller, 
+            get_time(), 
+            op, 
+            details_builder.build()
+        );
+    });
+}
+
+// logs are public, errors may echo a provider url with its api key
 fn add_log(log: String) {
     LOGS.with(|l| {
         let mut logs = l.borrow_mut();
         if logs.len() == 1000 {
             logs.pop_front();
         } 
-        logs.push_back(log);
+        logs.push_back(redact_text(&log));
     });
 }
 
@@ -7,24 +7,72 @@ use std::cell::{RefCell};
 use std::collections::{HashMap, VecDeque};
 
 use ic_web3::ic::get_eth_addr;
+use ic_web3::types::H256;
 
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
-use ic_cdk::export::candid::{candid_method};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update, heartbeat};
+use ic_cdk::export::candid::{candid_method, CandidType, Deserialize};
 use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
 use candid::types::principal::Principal;
+use ic_cron::task_scheduler::TaskScheduler;
+use ic_cron::types::Iterations;
 
 use omnic::utils::DetailsBuilder;
-use omnic::{Message, chains::EVMChainClient, ChainConfig, ChainState, ChainType};
+use omnic::{Message, BatchEntry, BatchOutcome, Deadline, chains::EVMChainClient, ChainConfig, ChainState, ChainStateV0, ChainType, PauseState, CanisterBalance};
+use omnic::chain_state::legacy_signer_path;
 use omnic::{HomeContract, DetailValue, Record};
-use omnic::consts::{KEY_NAME, MAX_RESP_BYTES, CYCLES_PER_CALL, CYCLES_PER_BYTE};
-use omnic::state::{StateInfo, RecordDB};
-use omnic::call::{call_to_canister, call_to_chain};
+use omnic::consts::{MAX_RESP_BYTES, CYCLES_PER_CALL, CYCLES_PER_BYTE};
+use omnic::state::{StateInfo, StateInfoV0, RecordDB, Role};
+use omnic::relayer::{RelayerDB, RelayerStats, RelayerRewards, RewardConfig};
+use omnic::fee::{CyclesLedger, FeeConfig, FeePayer, UnpaidMessage};
+use omnic::gas::{GasLedger, FeeSchedule, PnLReport, BalanceAlert, DeliveryReceipt};
+use omnic::outbound::{OutboundQueue, OutboundConfig, QueuedMessage};
+use omnic::rotation::{Rotation, ChainRotation, RotationStatus};
+use omnic::chains::set_omnic_canister_addr_calldata;
+use omnic::app::{AppRegistry, AppConfig};
+use omnic::ack::{Ack, IC_CHAIN_ID};
+use omnic::schema::{SchemaRegistry, PayloadSchema};
+use omnic::address::{recipient_principal, principal_to_h256};
+use omnic::ratelimit::{RateLimiter, RateLimit, DeferredMessage};
+use omnic::secret::{RpcSecrets, RpcCredential, CredentialPlacement, RpcEndpoint, redact_url, redact_urls, redact_text};
+use omnic::call::{call_to_canister, call_to_chain, call_to_chain_batch, get_app_version};
+use omnic_app::INTERFACE_VERSION;
+
+ic_cron::implement_cron!();
+
+// interval to fetch receipts of the txs sent to evm chains
+const SYNC_RECEIPTS_PERIOD: u64 = 1_000_000_000 * 60;
+// interval to check the balances of the proxy's evm addresses
+const CHECK_BALANCES_PERIOD: u64 = 1_000_000_000 * 300;
+// interval to deliver messages deferred by the rate limits
+const DRAIN_DEFERRED_PERIOD: u64 = 1_000_000_000 * 10;
+// interval to send the outbound queues that are due
+const FLUSH_OUTBOUND_PERIOD: u64 = 1_000_000_000 * 10;
+// result of a message queued for a processMessageBatch tx
+const QUEUED: &str = "queued";
+
+#[derive(CandidType, Deserialize, Clone)]
+enum Task {
+    SyncReceipts,
+    CheckBalances,
+    DrainDeferred,
+    FlushOutbound,
+}
 
 thread_local! {
     static STATE_INFO: RefCell<StateInfo> = RefCell::new(StateInfo::default());
     static CHAINS: RefCell<HashMap<u32, ChainState>>  = RefCell::new(HashMap::new());
     static LOGS: RefCell<VecDeque<String>> = RefCell::new(VecDeque::default());
     static RECORDS: RefCell<RecordDB> = RefCell::new(RecordDB::new());
+    static RPC_SECRETS: RefCell<RpcSecrets> = RefCell::new(RpcSecrets::new());
+    static RELAYERS: RefCell<RelayerDB> = RefCell::new(RelayerDB::new());
+    static CYCLES_LEDGER: RefCell<CyclesLedger> = RefCell::new(CyclesLedger::new());
+    static GAS_LEDGER: RefCell<GasLedger> = RefCell::new(GasLedger::new());
+    static ROTATION: RefCell<Rotation> = RefCell::new(Rotation::new());
+    static APPS: RefCell<AppRegistry> = RefCell::new(AppRegistry::new());
+    static LIMITER: RefCell<RateLimiter> = RefCell::new(RateLimiter::new());
+    static SCHEMAS: RefCell<SchemaRegistry> = RefCell::new(SchemaRegistry::new());
+    static OUTBOUND: RefCell<OutboundQueue> = RefCell::new(OutboundQueue::new());
 }
 
 #[query]
@@ -35,14 +83,52 @@ fn get_logs() -> Vec<String> {
     })
 }
 
+// key_name: threshold ecdsa key, "test_key_1" on mainnet, "dfx_test_key" on a local replica
 #[init]
 #[candid_method(init)]
-fn init() {
+fn init(key_name: String) {
     let caller = ic_cdk::api::caller();
     STATE_INFO.with(|info| {
         let mut info = info.borrow_mut();
-        info.add_owner(caller);
+        info.grant_role(caller, Role::Admin);
+        info.set_key_name(key_name);
     });
+    schedule_tasks();
+}
+
+fn schedule_tasks() {
+    cron_enqueue(
+        Task::SyncReceipts, 
+        ic_cron::types::SchedulingOptions {
+            delay_nano: SYNC_RECEIPTS_PERIOD,
+            interval_nano: SYNC_RECEIPTS_PERIOD,
+            iterations: Iterations::Infinite,
+        },
+    ).unwrap();
+    cron_enqueue(
+        Task::CheckBalances, 
+        ic_cron::types::SchedulingOptions {
+            delay_nano: CHECK_BALANCES_PERIOD,
+            interval_nano: CHECK_BALANCES_PERIOD,
+            iterations: Iterations::Infinite,
+        },
+    ).unwrap();
+    cron_enqueue(
+        Task::DrainDeferred, 
+        ic_cron::types::SchedulingOptions {
+            delay_nano: DRAIN_DEFERRED_PERIOD,
+            interval_nano: DRAIN_DEFERRED_PERIOD,
+            iterations: Iterations::Infinite,
+        },
+    ).unwrap();
+    cron_enqueue(
+        Task::FlushOutbound, 
+        ic_cron::types::SchedulingOptions {
+            delay_nano: FLUSH_OUTBOUND_PERIOD,
+            interval_nano: FLUSH_OUTBOUND_PERIOD,
+            iterations: Iterations::Infinite,
+        },
+    ).unwrap();
 }
 
 #[query]
@@ -52,14 +138,18 @@ async fn transform(raw: TransformArgs) -> HttpResponse {
     t
 }
 
-// get canister's evm address
+// get canister's evm address on `chain_id`
 #[update(name = "get_canister_addr")]
 #[candid_method(update, rename = "get_canister_addr")]
-async fn get_canister_addr(chain_type: ChainType) -> Result<String, String> {
+async fn get_canister_addr(chain_id: u32) -> Result<String, String> {
     let cid = ic_cdk::id();
-    let derivation_path = vec![cid.clone().as_slice().to_vec()];
+    let (chain_type, (key_name, derivation_path)) = CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let c = chains.get(&chain_id).ok_or("Chain id not exist".to_string())?;
+        Ok::<(ChainType, (String, Vec<Vec<u8>>)), String>((c.chain_type(), get_signer(c)))
+    })?;
     match chain_type {
-        ChainType::Evm => match get_eth_addr(Some(cid), Some(derivation_path), KEY_NAME.to_string()).await {
+        ChainType::Evm => match get_eth_addr(Some(cid), Some(derivation_path), key_name).await {
                 Ok(addr) => { Ok(hex::encode(addr)) },
                 Err(e) => { Err(e) },
             },
@@ -67,30 +157,330 @@ async fn get_canister_addr(chain_type: ChainType) -> Result<String, String> {
     }
 }
 
-#[update(guard = "is_authorized")]
+// derive a distinct evm address for each chain
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_canister_addrs")]
 async fn set_canister_addrs() -> Result<bool, String> {
     let cid = ic_cdk::id();
-    let derivation_path = vec![cid.clone().as_slice().to_vec()];
-    let evm_addr = get_eth_addr(Some(cid), Some(derivation_path), KEY_NAME.to_string())
-        .await
-        .map(|v| hex::encode(v))
-        .map_err(|e| format!("calc evm address failed: {:?}", e))?;
-    CHAINS.with(|chains| {
-        let mut chains = chains.borrow_mut();
-        for (_id, chain) in chains.iter_mut() {
-            match chain.chain_type() {
-                ChainType::Evm => chain.set_canister_addr(evm_addr.clone()),
+    let signers: Vec<(u32, (String, Vec<Vec<u8>>))> = CHAINS.with(|chains| {
+        chains.borrow().iter()
+            .filter(|(_, c)| match c.chain_type() {
+                ChainType::Evm => true,
                 _ => {
                     add_log("chain type not supported yet!".to_string());
+                    false
                 }
+            })
+            .map(|(id, c)| (*id, get_signer(c)))
+            .collect()
+    });
+    for (chain_id, (key_name, derivation_path)) in signers {
+        let evm_addr = get_eth_addr(Some(cid), Some(derivation_path), key_name)
+            .await
+            .map(|v| hex::encode(v))
+            .map_err(|e| format!("calc evm address failed: {:?}", e))?;
+        CHAINS.with(|chains| {
+            if let Some(chain) = chains.borrow_mut().get_mut(&chain_id) {
+                chain.set_canister_addr(evm_addr);
+            }
+        });
+    }
+    Ok(true)
+}
+
+// use a custom derivation path for the signer on `chain_id`, e.g. to rotate a compromised signer,
+// empty path for the default, call set_canister_addrs after this
+#[update(name = "set_derivation_path", guard = "is_admin")]
+#[candid_method(update, rename = "set_derivation_path")]
+fn set_derivation_path(chain_id: u32, derivation_path: Vec<Vec<u8>>) -> Result<bool, String> {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let c = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        c.set_derivation_path(derivation_path.clone());
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "set_derivation_path".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("derivation_path", DetailValue::Text(
+                derivation_path.iter().map(|p| hex::encode(p)).collect::<Vec<String>>().join("/")
+            ))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_key_name")]
+#[candid_method(query, rename = "get_key_name")]
+fn get_key_name() -> String {
+    STATE_INFO.with(|s| s.borrow().key_name.clone())
+}
+
+// move all evm chains to a new ecdsa key as a signer rotation: derive the address of each chain's
+// signer path under the new key and hold deliveries to it. the contract owner approves the new
+// addresses with approveOmnicCanisterAddr, then submit_rotation lets the old addresses hand over
+// and finalize_rotation switches all chains and the default key once every contract points to
+// the new address and the old addresses have no pending txs
+#[update(name = "migrate_key", guard = "is_admin")]
+#[candid_method(update, rename = "migrate_key")]
+async fn migrate_key(new_key_name: String) -> Result<u32, String> {
+    let epoch = ROTATION.with(|r| r.borrow_mut().start_key_migration(new_key_name.clone()))?;
+    // chains never used have nothing to hand over, they switch with the default key
+    let chains: Vec<(u32, String, Vec<Vec<u8>>)> = CHAINS.with(|chains| {
+        chains.borrow().values()
+            .filter(|c| matches!(c.chain_type(), ChainType::Evm) && c.canister_addr != "")
+            .map(|c| (c.config.chain_id, c.canister_addr.clone(), get_signer(c)))
+            .filter(|(_, _, (key_name, _))| *key_name != new_key_name)
+            .map(|(chain_id, old_addr, (_, derivation_path))| (chain_id, old_addr, derivation_path))
+            .collect()
+    });
+    for (chain_id, old_addr, derivation_path) in chains {
+        match derive_rotation(chain_id, old_addr, new_key_name.clone(), derivation_path, true).await {
+            Ok(rotation) => ROTATION.with(|r| r.borrow_mut().add_chain(rotation)),
+            Err(e) => {
+                ROTATION.with(|r| r.borrow_mut().cancel());
+                return Err(format!("derive new address on chain {} failed: {}", chain_id, e));
+            }
+        }
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "migrate_key".to_string(), 
+        DetailsBuilder::new()
+            .insert("epoch", DetailValue::U64(epoch as u64))
+            .insert("key_name", DetailValue::Text(new_key_name))
+    );
+    Ok(epoch)
+}
+
+// derive the new signer of a chain and the calldata handing the contract over to it
+async fn derive_rotation(
+    chain_id: u32,
+    old_addr: String,
+    key_name: String,
+    new_derivation_path: Vec<Vec<u8>>,
+    submit: bool,
+) -> Result<ChainRotation, String> {
+    let new_addr = get_eth_addr(Some(ic_cdk::id()), Some(new_derivation_path.clone()), key_name)
+        .await
+        .map(|v| hex::encode(v))?;
+    let calldata = set_omnic_canister_addr_calldata(&new_addr).map_err(|e| format!("{:?}", e))?;
+    Ok(ChainRotation {
+        chain_id,
+        old_addr,
+        new_addr,
+        new_derivation_path,
+        calldata,
+        status: if submit { RotationStatus::Derived } else { RotationStatus::CalldataEmitted },
+    })
+}
+
+// signer rotation:
+// 1. start_rotation derives a new address for each chain and holds deliveries to them
+// 2. the contract owner approves the new addresses with approveOmnicCanisterAddr,
+//    then submit_rotation lets the old addresses hand over;
+//    or without submit, the owner multisig sends the calldata from get_rotation
+// 3. finalize_rotation switches all chains once every contract points to the new address
+//    and the old addresses have no pending txs
+#[update(name = "start_rotation", guard = "is_admin")]
+#[candid_method(update, rename = "start_rotation")]
+async fn start_rotation(chain_ids: Vec<u32>, submit: bool) -> Result<u32, String> {
+    let cid = ic_cdk::id();
+    let epoch = ROTATION.with(|r| r.borrow_mut().start(submit))?;
+    // empty for all evm chains
+    let chains: Vec<(u32, String, String)> = CHAINS.with(|chains| {
+        chains.borrow().values()
+            .filter(|c| matches!(c.chain_type(), ChainType::Evm) && c.canister_addr != "")
+            .filter(|c| chain_ids.is_empty() || chain_ids.contains(&c.config.chain_id))
+            .map(|c| (c.config.chain_id, c.canister_addr.clone(), get_signer(c).0))
+            .collect()
+    });
+    for (chain_id, old_addr, key_name) in chains {
+        let new_derivation_path = vec![cid.as_slice().to_vec(), chain_id.to_be_bytes().to_vec(), epoch.to_be_bytes().to_vec()];
+        match derive_rotation(chain_id, old_addr, key_name, new_derivation_path, submit).await {
+            Ok(rotation) => ROTATION.with(|r| r.borrow_mut().add_chain(rotation)),
+            Err(e) => {
+                ROTATION.with(|r| r.borrow_mut().cancel());
+                return Err(format!("derive new address on chain {} failed: {}", chain_id, e));
+            }
+        }
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "start_rotation".to_string(), 
+        DetailsBuilder::new()
+            .insert("epoch", DetailValue::U64(epoch as u64))
+            .insert("submit", DetailValue::Text(submit.to_string()))
+    );
+    Ok(epoch)
+}
+
+// send setOmnicCanisterAddr from the old address of each chain, the handover is mined after
+// the txs already sent from the old address since it takes the next nonce
+#[update(name = "submit_rotation", guard = "is_admin")]
+#[candid_method(update, rename = "submit_rotation")]
+async fn submit_rotation() -> Result<bool, String> {
+    let chains: Vec<ChainRotation> = ROTATION.with(|r| {
+        let r = r.borrow();
+        r.chains.values()
+            .filter(|c| r.active && r.submit && c.status == RotationStatus::Derived)
+            .cloned()
+            .collect()
+    });
+    let mut failed = Vec::new();
+    for rotation in chains {
+        let chain = CHAINS.with(|chains| {
+            chains.borrow().get(&rotation.chain_id).map(|c| (c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone(), get_signer(c)))
+        });
+        let (omnic_addr, rpc, (key_name, derivation_path)) = match chain {
+            Some(v) => v,
+            None => continue, // chain deleted
+        };
+        let res = match EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL) {
+            Ok(client) => client
+                .with_signer(key_name, derivation_path)
+                .set_omnic_canister_addr(rotation.old_addr.clone(), rotation.chain_id, rotation.new_addr.clone())
+                .await
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("init client failed: {:?}", e)),
+        };
+        match res {
+            Ok(txhash) => {
+                let txhash = hex::encode(txhash);
+                ROTATION.with(|r| r.borrow_mut().set_status(rotation.chain_id, RotationStatus::Submitted(txhash.clone())));
+                add_record(
+                    ic_cdk::caller(), 
+                    "submit_rotation".to_string(), 
+                    DetailsBuilder::new()
+                        .insert("chain_id", DetailValue::U64(rotation.chain_id as u64))
+                        .insert("new_addr", DetailValue::Text(rotation.new_addr.clone()))
+                        .insert("txhash", DetailValue::Text(txhash))
+                );
+            }
+            Err(e) => {
+                add_log(format!("submit rotation on chain {} failed: {}", rotation.chain_id, e));
+                failed.push(rotation.chain_id);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("submit rotation failed on chains: {:?}", failed));
+    }
+    Ok(true)
+}
+
+#[update(name = "finalize_rotation", guard = "is_admin")]
+#[candid_method(update, rename = "finalize_rotation")]
+async fn finalize_rotation() -> Result<bool, String> {
+    let chains: Vec<ChainRotation> = ROTATION.with(|r| {
+        let r = r.borrow();
+        r.chains.values()
+            .filter(|c| r.active && c.status != RotationStatus::Confirmed)
+            .cloned()
+            .collect()
+    });
+    for rotation in chains {
+        let chain = CHAINS.with(|chains| {
+            chains.borrow().get(&rotation.chain_id).map(|c| (c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone()))
+        });
+        let (omnic_addr, rpc) = match chain {
+            Some(v) => v,
+            None => continue,
+        };
+        let client = match EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL) {
+            Ok(c) => c,
+            Err(e) => {
+                add_log(format!("init client failed: {:?}", e));
+                continue;
+            }
+        };
+        let switched = match client.get_proxy_canister_addr().await {
+            Ok(addr) => addr.eq_ignore_ascii_case(rotation.new_addr.trim_start_matches("0x")),
+            Err(e) => {
+                add_log(format!("get proxy canister addr on chain {} failed: {:?}", rotation.chain_id, e));
+                false
+            }
+        };
+        if !switched {
+            continue;
+        }
+        match client.has_pending_txs(rotation.old_addr.clone()).await {
+            Ok(false) => {
+                ROTATION.with(|r| r.borrow_mut().set_status(rotation.chain_id, RotationStatus::Confirmed));
+            }
+            Ok(true) => {}
+            Err(e) => {
+                add_log(format!("get pending txs on chain {} failed: {:?}", rotation.chain_id, e));
+            }
+        }
+    }
+    // check again after the awaits, the rotation may be cancelled meanwhile
+    let done = ROTATION.with(|r| {
+        let mut r = r.borrow_mut();
+        let key_name = r.key_name.clone();
+        r.finish_confirmed().map(|done| (done, key_name))
+    });
+    let (done, key_name) = match done {
+        Some(v) => v,
+        None => {
+            let pending: Vec<u32> = ROTATION.with(|r| {
+                r.borrow().chains.values()
+                    .filter(|c| c.status != RotationStatus::Confirmed)
+                    .map(|c| c.chain_id)
+                    .collect()
+            });
+            return Err(format!("rotation not confirmed on chains: {:?}", pending));
+        }
+    };
+    // switch every chain in this one update
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        for rotation in done.iter() {
+            if let Some(c) = chains.get_mut(&rotation.chain_id) {
+                rotation.switch(c);
+            }
+        }
+        // a key migration moves every chain to the new default key
+        if !key_name.is_empty() {
+            for c in chains.values_mut() {
+                c.key_name = String::new();
             }
         }
     });
+    if !key_name.is_empty() {
+        STATE_INFO.with(|s| s.borrow_mut().set_key_name(key_name.clone()));
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "finalize_rotation".to_string(), 
+        DetailsBuilder::new()
+            .insert("chains", DetailValue::Text(format!("{:?}", done.iter().map(|r| r.chain_id).collect::<Vec<u32>>())))
+            .insert("key_name", DetailValue::Text(key_name))
+    );
+    Ok(true)
+}
+
+// stop holding deliveries, only safe if no contract has switched to the new address
+#[update(name = "cancel_rotation", guard = "is_admin")]
+#[candid_method(update, rename = "cancel_rotation")]
+fn cancel_rotation() -> Result<bool, String> {
+    ROTATION.with(|r| r.borrow_mut().cancel());
+    add_record(
+        ic_cdk::caller(), 
+        "cancel_rotation".to_string(), 
+        DetailsBuilder::new()
+    );
     Ok(true)
 }
 
-#[update(guard = "is_authorized")]
+#[query(name = "get_rotation", guard = "is_operator")]
+#[candid_method(query, rename = "get_rotation")]
+fn get_rotation() -> Rotation {
+    ROTATION.with(|r| r.borrow().clone())
+}
+
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_fetch_period")]
 async fn set_fetch_period(fetch_root_period: u64, fetch_roots_period: u64) -> Result<bool, String> {
     STATE_INFO.with(|s| {
@@ -100,7 +490,7 @@ async fn set_fetch_period(fetch_root_period: u64, fetch_roots_period: u64) -> Re
     Ok(true)
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "add_chain")]
 fn add_chain(
     chain_id: u32, 
@@ -133,7 +523,7 @@ fn add_chain(
         "add_chain".to_string(), 
         DetailsBuilder::new()
             .insert("chain_id", DetailValue::U64(chain_id as u64))
-            .insert("urls", DetailValue::Text(urls.join(",")))
+            .insert("urls", DetailValue::Text(redact_urls(&urls).join(",")))
             .insert("gatewat_addr", DetailValue::Principal(gateway_canister_addr))
             .insert("omnic_addr", DetailValue::Text(omnic_addr))
             .insert("start_block", DetailValue::U64(start_block))
@@ -141,7 +531,7 @@ fn add_chain(
     Ok(true)
 }
 
-#[update(name = "delete_chain", guard = "is_authorized")]
+#[update(name = "delete_chain", guard = "is_admin")]
 #[candid_method(update, rename = "delete_chain")]
 fn delete_chain(chain_id: u32) -> Result<bool, String> {
     match CHAINS.with(|c| {
@@ -162,7 +552,7 @@ fn delete_chain(chain_id: u32) -> Result<bool, String> {
 }
 
 // update chain settings
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "update_chain")]
 fn update_chain(
     chain_id: u32, 
@@ -171,28 +561,26 @@ fn update_chain(
     omnic_addr: String, 
     start_block: u64
 ) -> Result<bool, String> {
-    // add chain config
+    // update chain config in place, keep roots, next_index and canister_addr
     CHAINS.with(|chains| {
         let mut chains = chains.borrow_mut();
-        if chains.contains_key(&chain_id) {
-            chains.insert(chain_id, ChainState::new(
-                ChainConfig::new(
-                    ChainType::Evm,
-                    chain_id,
-                    urls.clone(),
-                    gateway_canister_addr,
-                    omnic_addr.clone(),
-                    start_block,
-                )
-            ));
-        }
-    });
+        let chain = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        chain.update_config(ChainConfig::new(
+            ChainType::Evm,
+            chain_id,
+            urls.clone(),
+            gateway_canister_addr,
+            omnic_addr.clone(),
+            start_block,
+        ));
+        Ok::<(), String>(())
+    })?;
     add_record(
         ic_cdk::caller(), 
         "update_chain".to_string(), 
         DetailsBuilder::new()
             .insert("chain_id", DetailValue::U64(chain_id as u64))
-            .insert("urls", DetailValue::Text(urls.join(",")))
+            .insert("urls", DetailValue::Text(redact_urls(&urls).join(",")))
             .insert("gateway_addr", DetailValue::Principal(gateway_canister_addr))
             .insert("omnic_addr", DetailValue::Text(omnic_addr))
             .insert("start_block", DetailValue::U64(start_block))
@@ -200,8 +588,74 @@ fn update_chain(
     Ok(true)
 }
 
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "add_urls")]
+fn add_urls(chain_id: u32, urls: Vec<String>) -> Result<bool, String> {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let chain = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        chain.add_urls(urls.clone());
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "add_urls".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("urls", DetailValue::Text(redact_urls(&urls).join(",")))
+    );
+    Ok(true)
+}
+
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "remove_url")]
+fn remove_url(chain_id: u32, url: String) -> Result<bool, String> {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let chain = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        if chain.rpc_urls().len() == 1 && chain.rpc_urls()[0] == url {
+            return Err("can not remove the last rpc url".to_string());
+        }
+        if !chain.remove_url(&url) {
+            return Err("url not exist".to_string());
+        }
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "remove_url".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("url", DetailValue::Text(redact_url(&url)))
+    );
+    Ok(true)
+}
+
+// replace all rpc urls of a chain, e.g. when rotating api keys
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "replace_urls")]
+fn replace_urls(chain_id: u32, urls: Vec<String>) -> Result<bool, String> {
+    if urls.is_empty() {
+        return Err("rpc urls can not be empty".to_string());
+    }
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let chain = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        chain.replace_urls(urls.clone());
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "replace_urls".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("urls", DetailValue::Text(redact_urls(&urls).join(",")))
+    );
+    Ok(true)
+}
+
 // update chain settings
-#[update(guard = "is_authorized")]
+#[update(guard = "is_admin")]
 #[candid_method(update, rename = "set_next_index")]
 fn set_next_index(
     chain_id: u32, 
@@ -229,7 +683,101 @@ fn get_chains() -> Result<Vec<ChainState>, String> {
     // add chain config
     CHAINS.with(|chains| {
         let chains = chains.borrow();
-        Ok(chains.clone().into_iter().map(|(_id, c)| c).collect())
+        Ok(chains.values().map(|c| c.redacted()).collect())
+    })
+}
+
+// raw rpc urls, may contain api keys
+#[query(name = "get_rpc_urls", guard = "is_operator")]
+#[candid_method(query, rename = "get_rpc_urls")]
+fn get_rpc_urls(chain_id: u32) -> Result<Vec<String>, String> {
+    CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let chain = chains.get(&chain_id).ok_or("Chain id not exist".to_string())?;
+        Ok(chain.rpc_urls())
+    })
+}
+
+// set the api key of a rpc provider url, the secret is never returned by any query
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_rpc_credential")]
+fn set_rpc_credential(url: String, placement: CredentialPlacement, secret: String) -> Result<bool, String> {
+    let placement_name = match &placement {
+        CredentialPlacement::Path => "path".to_string(),
+        CredentialPlacement::Query(name) => format!("query:{}", name),
+        CredentialPlacement::Header(name) => format!("header:{}", name),
+    };
+    RPC_SECRETS.with(|s| {
+        s.borrow_mut().set_credential(url.clone(), RpcCredential { placement, secret });
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_rpc_credential".to_string(), 
+        DetailsBuilder::new()
+            .insert("url", DetailValue::Text(redact_url(&url)))
+            .insert("placement", DetailValue::Text(placement_name))
+    );
+    Ok(true)
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "remove_rpc_credential")]
+fn remove_rpc_credential(url: String) -> Result<bool, String> {
+    if !RPC_SECRETS.with(|s| s.borrow_mut().remove_credential(&url)) {
+        return Err("credential not exist".to_string());
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "remove_rpc_credential".to_string(), 
+        DetailsBuilder::new()
+            .insert("url", DetailValue::Text(redact_url(&url)))
+    );
+    Ok(true)
+}
+
+#[update(guard = "is_operator")]
+#[candid_method(update, rename = "set_paused")]
+fn set_paused(chain_id: u32, origin_paused: bool, destination_paused: bool) -> Result<bool, String> {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let chain = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        chain.set_paused(origin_paused, destination_paused);
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "set_paused".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("origin_paused", if origin_paused { DetailValue::True } else { DetailValue::False })
+            .insert("destination_paused", if destination_paused { DetailValue::True } else { DetailValue::False })
+    );
+    Ok(true)
+}
+
+#[update(guard = "is_admin")]
+#[candid_method(update, rename = "set_breaker_threshold")]
+fn set_breaker_threshold(threshold: u32) -> Result<bool, String> {
+    STATE_INFO.with(|s| {
+        s.borrow_mut().set_breaker_threshold(threshold);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_breaker_threshold".to_string(), 
+        DetailsBuilder::new()
+            .insert("threshold", DetailValue::U64(threshold as u64))
+    );
+    Ok(true)
+}
+
+// relayer can check this before submitting messages
+#[query(name = "get_pause_state")]
+#[candid_method(query, rename = "get_pause_state")]
+fn get_pause_state(chain_id: u32) -> Result<PauseState, String> {
+    CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let chain = chains.get(&chain_id).ok_or("Chain id not exist".to_string())?;
+        Ok(chain.pause_state())
     })
 }
 
@@ -242,7 +790,7 @@ async fn fetch(chain_id: u32, height: u64) -> Result<(String, u64, u64), String>
         (c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone())
     });
 
-    let client = EVMChainClient::new(rpc, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
     
     let start_cycles = ic_cdk::api::canister_balance();
@@ -284,7 +832,7 @@ async fn get_tx_count(chain_id: u32, addr: String) -> Result<u64, String> {
         _ => return Err("chain type not supported yet".into()),
     }
 
-    let client = EVMChainClient::new(rpc_url, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc_url), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
 
     client.get_tx_count(addr)
@@ -315,7 +863,7 @@ async fn get_gas_price(chain_id: u32) -> Result<u64, String> {
         _ => return Err("chain type not supported yet".into()),
     }
 
-    let client = EVMChainClient::new(rpc_url, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc_url), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
 
     client.get_gas_price()
@@ -350,6 +898,32 @@ async fn is_valid(message: Vec<u8>, proof: Vec<Vec<u8>>, leaf_index: u32) -> Res
     }
 }
 
+// check several messages from one origin against one root with a multiproof
+#[update(name = "is_valid_batch")]
+#[candid_method(query, rename = "is_valid_batch")]
+async fn is_valid_batch(messages: Vec<Vec<u8>>, leaf_indexes: Vec<u32>, nodes: Vec<Vec<u8>>) -> Result<bool, String> {
+    let first = messages.first().ok_or("no messages".to_string())?;
+    let m = Message::from_raw(first.clone()).map_err(|e| {
+        format!("parse message from bytes failed: {:?}", e)
+    })?;
+    // call to gate way canister
+    let gateway: Principal = CHAINS.with(|c| {
+        let chains = c.borrow();
+        let chain = chains.get(&m.origin).ok_or("src chain id not exist".to_string())?;
+        Ok::<Principal, String>(chain.config.gateway_addr)
+    })?;
+
+    let res = ic_cdk::call(gateway, "is_valid_batch", (messages, leaf_indexes, nodes, )).await;
+    match res {
+        Ok((validation_result, )) => {
+            validation_result
+        }
+        Err((_code, msg)) => {
+            Err(msg)
+        }
+    }
+}
+
 #[update(name = "get_latest_root")]
 #[candid_method(query, rename = "get_latest_root")]
 async fn get_latest_root(chain_id: u32) -> Result<String, String> {
@@ -394,7 +968,7 @@ async fn send_raw_tx(dst_chain: u32, raw_tx: Vec<u8>) -> Result<Vec<u8>, String>
         _ => return Err("chain type not supported yet".into()),
     }
 
-    let client = EVMChainClient::new(rpc_url, omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+    let client = EVMChainClient::new(get_endpoint(&rpc_url), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
         .map_err(|e| format!("init client failed: {:?}", e))?;
 
     // client.send_raw_tx will always end up with error because the same tx will be submitted multiple times 
@@ -410,19 +984,30 @@ async fn send_raw_tx(dst_chain: u32, raw_tx: Vec<u8>) -> Result<Vec<u8>, String>
 #[candid_method(update, rename = "process_message")]
 async fn process_message(message: Vec<u8>, proof: Vec<Vec<u8>>, leaf_index: u32) -> Result<(String, u64), String> {
     let origin_caller = ic_cdk::caller();
+    check_relayer(origin_caller)?;
+    RELAYERS.with(|r| r.borrow_mut().incr_submitted(origin_caller));
     // verify message proof: use proof, message to calculate the merkle root, 
     // check if the root exists in corresponding chain state
     // if valid, call dest canister.handleMessage or send tx to dest chain
     // if invalid, return error
     add_log(format!("got message: {:?}", leaf_index));
+    let m = match Message::from_raw(message.clone()) {
+        Ok(m) => m,
+        Err(e) => {
+            RELAYERS.with(|r| r.borrow_mut().incr_invalid(origin_caller));
+            return Err(format!("parse message from bytes failed: {:?}", e));
+        }
+    };
+    check_route(&m)?;
     let valid = is_valid(message.clone(), proof, leaf_index).await?;
     if !valid {
+        RELAYERS.with(|r| r.borrow_mut().incr_invalid(origin_caller));
         add_log("message does not pass verification!".to_string());
         return Err("message does not pass verification!".into());
     }
-    let m = Message::from_raw(message.clone()).map_err(|e| {
-        format!("parse message from bytes failed: {:?}", e)
-    })?;
+    RELAYERS.with(|r| r.borrow_mut().incr_valid(origin_caller));
+    // awaited before the index check, nothing else may run between the check and the bump
+    let expired = check_deadline(&m).await?;
     // check leaf_index == next_index, then bump next_index
     let next_index = CHAINS.with(|chains| {
         let chains = chains.borrow();
@@ -433,82 +1018,994 @@ async fn process_message(message: Vec<u8>, proof: Vec<Vec<u8>>, leaf_index: u32)
         add_log(format!("next_index: {} != leaf_index: {}, ", next_index, leaf_index));
         return Err(format!("next_index: {} != leaf_index: {}, ", next_index, leaf_index));
     }
-    CHAINS.with(|chains| {
-        let mut chains = chains.borrow_mut();
-        let c = chains.get_mut(&m.origin).expect("chain not found");
-        c.bump_index();
-    });
-    // send msg to destination
-    // TODO reset next index after call error?
-    let res = if m.destination == 0 {
-        // take last 10 bytes
-        let recipient = Principal::from_slice(&m.recipient.as_bytes()[22..]);
-        add_log(format!("recipient: {:?}", Principal::to_text(&recipient)));
-        call_to_canister(recipient, m.to_leaf().0.to_vec(), &m).await
+    // valid but stale, the index moves on and it is dropped
+    if let Some(reason) = expired {
+        bump_index(m.origin);
+        return Err(expire_message(origin_caller, &m, None, reason).await);
+    }
+    // ic apps that opted in only get the messages their config allows,
+    // the message is valid so the index moves on and it is dropped,
+    // entries of a batch are checked one by one when it is delivered
+    let checked = if m.is_batch() {
+        m.batch_entries().map(|_| ()).map_err(|e| format!("invalid batch: {:?}", e))
+    } else if m.destination == 0 {
+        check_app(&m)
     } else {
-        // send tx to dst chain
-        // call_to_chain(m.destination, message).await
-        let (caller, omnic_addr, rpc) = CHAINS.with(|chains| {
-            let chains = chains.borrow();
-            let c = chains.get(&m.destination).expect("chain not found");
-            (c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone())
-        });
-        if caller == "" || omnic_addr == "" {
-            return Err("caller address is empty".into());
+        Ok(())
+    };
+    if let Err(e) = checked {
+        bump_index(m.origin);
+        record_message(origin_caller, &m, None, &Err(e.clone()));
+        return Err(e);
+    }
+    // only the first valid submission of a leaf can bump the index, its relayer is rewarded once delivered.
+    // an unpaid message must not block the ones behind it, it is kept until paid with pay_message
+    let payer = match charge_fee(&m, message.len()) {
+        Ok(payer) => payer,
+        Err(e) => {
+            bump_index(m.origin);
+            let fee = CYCLES_LEDGER.with(|l| {
+                let mut l = l.borrow_mut();
+                let fee = l.fee(m.destination, message.len());
+                l.add_unpaid(hex::encode(m.to_leaf()), UnpaidMessage { message, relayer: origin_caller, fee });
+                fee
+            });
+            let e = format!("unpaid: {}", e);
+            add_log(format!("message {} {}, {} cycles due", m, e, fee));
+            record_message(origin_caller, &m, None, &Err(e.clone()));
+            return Err(e);
         }
-        call_to_chain(caller, omnic_addr, rpc, m.destination, message).await
     };
-    
-    add_record(
-        origin_caller, 
-        "process_message".to_string(), 
-        DetailsBuilder::new()
-            .insert("origin", DetailValue::U64(m.origin as u64))
-            .insert("send", DetailValue::Text(m.sender.to_string()))
-            .insert("nonce", DetailValue::U64(m.nonce as u64))
-            .insert("destination", DetailValue::U64(m.destination as u64))
-            .insert("recipient", DetailValue::Text(m.recipient.to_string()))
-            .insert("result", DetailValue::Text(
-                match res.clone() {
-                    Ok(o) => {
-                        o
-                    }
-                    Err(e) => {
-                        e
-                    }
-                }
-            ))
-    );
-    
-    res.map(|o| (o, ic_cdk::api::time()))
-}
-
-#[update(name = "add_owner", guard = "is_authorized")]
-#[candid_method(update, rename = "add_owner")]
-async fn add_owner(owner: Principal) {
-    STATE_INFO.with(|s| {
-        s.borrow_mut().add_owner(owner);
-    });
+    bump_index(m.origin);
+    dispatch(origin_caller, &m, message, payer).await.map(|o| (o, ic_cdk::api::time()))
 }
 
-#[update(name = "remove_owner", guard = "is_authorized")]
-#[candid_method(update, rename = "remove_owner")]
-async fn remove_owner(owner: Principal) {
-    STATE_INFO.with(|s| {
-        s.borrow_mut().delete_owner(owner);
-    });
+// pay the fee of an unpaid message, with attached cycles or the prepaid balance of the recipient app,
+// and deliver it
+#[update(name = "pay_message")]
+#[candid_method(update, rename = "pay_message")]
+async fn pay_message(msg_hash: String) -> Result<String, String> {
+    let unpaid = CYCLES_LEDGER.with(|l| l.borrow_mut().take_unpaid(&msg_hash))
+        .ok_or("message is not unpaid".to_string())?;
+    // parsed before it was kept
+    let m = Message::from_raw(unpaid.message.clone()).expect("unpaid message invalid");
+    let checked = match check_route(&m) {
+        Ok(()) => match check_deadline(&m).await {
+            Ok(Some(reason)) => return Err(expire_message(unpaid.relayer, &m, None, reason).await),
+            Ok(None) => charge_fee(&m, unpaid.message.len()),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    let payer = match checked {
+        Ok(payer) => payer,
+        Err(e) => {
+            CYCLES_LEDGER.with(|l| l.borrow_mut().add_unpaid(msg_hash, unpaid));
+            return Err(e);
+        }
+    };
+    dispatch(unpaid.relayer, &m, unpaid.message, payer).await
 }
 
-#[query(name = "get_record_size", guard = "is_authorized")]
-#[candid_method(query, rename = "get_record_size")]
-fn get_record_size(operation: Option<String>) -> usize {
-    RECORDS.with(|r| {
-        let records = r.borrow();
-        records.size(operation)
+#[query(name = "get_unpaid_messages")]
+#[candid_method(query, rename = "get_unpaid_messages")]
+fn get_unpaid_messages() -> Vec<(String, u64)> {
+    CYCLES_LEDGER.with(|l| {
+        l.borrow().unpaid.iter().map(|(hash, u)| (hash.clone(), u.fee)).collect()
     })
 }
 
-#[query(name = "get_record", guard = "is_authorized")]
+// deliver a paid message now, or defer it if it is over the rate limits
+async fn dispatch(relayer: Principal, m: &Message, message: Vec<u8>, payer: FeePayer) -> Result<String, String> {
+    // over the rate limit, or behind such messages: deliver later in order
+    let sender_key = (m.origin, m.sender.as_bytes().to_vec());
+    let recipient_key = (m.destination, m.recipient.as_bytes().to_vec());
+    let recipient_limit = app_rate_limit(m);
+    let now = ic_cdk::api::time();
+    let admitted = LIMITER.with(|l| {
+        let mut l = l.borrow_mut();
+        !l.is_blocked(&sender_key, &recipient_key) && l.try_acquire(&sender_key, &recipient_key, recipient_limit, now)
+    });
+    if !admitted {
+        let deferred = LIMITER.with(|l| l.borrow_mut().defer(DeferredMessage {
+            message,
+            relayer,
+            payer,
+            sender_key,
+            recipient_key,
+            deferred_at: now,
+        }));
+        // a full queue drops the messages over the limits, so a spammer can not grow it
+        let res = if deferred {
+            Ok("deferred".to_string())
+        } else {
+            Err("rate limited: too many deferred messages".to_string())
+        };
+        record_message(relayer, m, Some(payer), &res);
+        return res;
+    }
+    // TODO reset next index after call error?
+    let res = deliver(relayer, m, message).await;
+    if is_delivered(m, &res) {
+        reward_delivery(relayer, m);
+    }
+    record_message(relayer, m, Some(payer), &res);
+    res
+}
+
+// count a delivered message for its relayer and credit the reward,
+// the native reward is capped by the source fee mirrored in the gas ledger
+fn reward_delivery(relayer: Principal, m: &Message) {
+    let fee_paid = GAS_LEDGER.with(|g| g.borrow().source_fee(m.origin, m.body.len()));
+    credit_delivery(relayer, m.origin, fee_paid);
+}
+
+fn credit_delivery(relayer: Principal, origin: u32, fee_paid: u128) {
+    RELAYERS.with(|r| {
+        let mut r = r.borrow_mut();
+        r.incr_delivered(relayer);
+        r.credit_reward(relayer, origin, fee_paid);
+    });
+}
+
+// an ic message is delivered once its recipient returned, a message to an evm chain
+// once the receipt of its tx shows it succeeded, see sync_receipts
+fn is_delivered(m: &Message, res: &Result<String, String>) -> bool {
+    m.destination == 0 && res.is_ok()
+}
+
+// check an ic message against the config of its recipient app
+fn check_app(m: &Message) -> Result<(), String> {
+    let recipient = recipient_principal(&m.recipient)
+        .map_err(|e| format!("invalid recipient: {}", e))?;
+    APPS.with(|a| {
+        a.borrow().check(recipient, m.origin, m.sender.as_bytes(), m.body.len())
+            .map_err(|e| format!("rejected by app: {}", e))
+    })
+}
+
+// send msg to destination
+async fn deliver(relayer: Principal, m: &Message, message: Vec<u8>) -> Result<String, String> {
+    if m.is_batch() {
+        deliver_batch(relayer, m, message).await
+    } else if m.destination == 0 {
+        deliver_to_canister(m, m.to_leaf()).await
+    } else {
+        deliver_to_chain(Some(relayer), m, message).await
+    }
+}
+
+// notify the recipient canister, `leaf` is the leaf of the message in the origin tree
+async fn deliver_to_canister(m: &Message, leaf: H256) -> Result<String, String> {
+    let recipient = recipient_principal(&m.recipient)?;
+    add_log(format!("recipient: {:?}", Principal::to_text(&recipient)));
+    let res = call_to_canister(recipient, leaf.0.to_vec(), m).await;
+    if APPS.with(|a| a.borrow().ack_enabled(&recipient)) {
+        send_ack(m, leaf, recipient, &res).await;
+    }
+    res
+}
+
+// deliver the entries of a batch, each entry is recorded separately.
+// evm entries are sent in one processMessages tx, their results are recorded from its logs, see sync_receipts
+async fn deliver_batch(relayer: Principal, m: &Message, message: Vec<u8>) -> Result<String, String> {
+    let entries = m.batch_entries().map_err(|e| format!("invalid batch: {:?}", e))?;
+    if m.destination != 0 {
+        let res = deliver_to_chain(Some(relayer), m, message).await;
+        if res.is_err() {
+            for (i, entry) in entries.iter().enumerate() {
+                record_entry(relayer, m, i, entry, &res);
+            }
+        }
+        return res;
+    }
+    let leaf = m.to_leaf();
+    let mut outcome = BatchOutcome::new(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let em = m.entry_message(entry);
+        let res = match check_app(&em) {
+            Ok(()) => deliver_to_canister(&em, leaf).await,
+            Err(e) => Err(e),
+        };
+        outcome.record(i, &res);
+        record_entry(relayer, m, i, entry, &res);
+    }
+    // nothing delivered, the relayer is not rewarded
+    if outcome.delivered == 0 {
+        return Err(outcome.summary());
+    }
+    Ok(outcome.summary())
+}
+
+// send tx to the evm destination chain, or queue it for the next processMessageBatch,
+// `relayer` is rewarded once the receipt shows the message succeeded
+async fn deliver_to_chain(relayer: Option<Principal>, m: &Message, message: Vec<u8>) -> Result<String, String> {
+    let sent = QueuedMessage {
+        message,
+        origin: m.origin,
+        nonce: m.nonce,
+        message_hash: hex::encode(m.to_leaf()),
+        payload_len: m.body.len() as u64,
+        queued_at: get_time(),
+        relayer,
+    };
+    let full = OUTBOUND.with(|o| {
+        let mut o = o.borrow_mut();
+        if !o.enabled() {
+            return None;
+        }
+        Some(o.push(m.destination, sent.clone()))
+    });
+    match full {
+        None => send_to_chain(m, sent).await,
+        Some(full) => {
+            if full {
+                flush_outbound(m.destination).await;
+            }
+            Ok(QUEUED.into())
+        }
+    }
+}
+
+async fn send_to_chain(m: &Message, sent: QueuedMessage) -> Result<String, String> {
+    let (caller, omnic_addr, rpc, (key_name, derivation_path)) = CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let c = chains.get(&m.destination).expect("chain not found");
+        (c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone(), get_signer(c))
+    });
+    if caller == "" || omnic_addr == "" {
+        return Err("caller address is empty".into());
+    }
+    let res = call_to_chain(caller, omnic_addr, get_endpoint(&rpc), key_name, derivation_path, m.destination, sent.message.clone(), m.is_batch()).await;
+    match &res {
+        // the proxy pays gas for this tx, settle it against the source fee and record the result once mined
+        Ok(txhash) => GAS_LEDGER.with(|g| g.borrow_mut().add_pending(txhash.clone(), m.destination, &sent, 1)),
+        Err(_) => record_delivery(m.destination, false),
+    }
+    res
+}
+
+// send the next batch queued for the chain in one processMessageBatch tx,
+// the batch is put back if the chain can not be sent to now or the tx fails.
+// a chain has one batch in flight at a time, a flush meanwhile sends nothing
+async fn flush_outbound(chain_id: u32) {
+    let batch = OUTBOUND.with(|o| o.borrow_mut().take_batch(chain_id));
+    if batch.is_empty() {
+        return;
+    }
+    let target = CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        check_destination(&chains, chain_id)?;
+        let c = chains.get(&chain_id).ok_or("dst chain id not exist".to_string())?;
+        if c.canister_addr == "" || c.config.omnic_addr == "" {
+            return Err("caller address is empty".to_string());
+        }
+        Ok((c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone(), get_signer(c)))
+    });
+    let res = match target {
+        Ok((caller, omnic_addr, rpc, (key_name, derivation_path))) => {
+            let msgs = batch.iter().map(|q| q.message.clone()).collect();
+            let res = call_to_chain_batch(caller, omnic_addr, get_endpoint(&rpc), key_name, derivation_path, chain_id, msgs).await;
+            // the result of each message is recorded from the receipt
+            if res.is_err() {
+                record_delivery(chain_id, false);
+            }
+            res
+        }
+        Err(e) => Err(e),
+    };
+    let batch_size = batch.len() as u32;
+    for q in batch.iter() {
+        add_record(
+            ic_cdk::id(),
+            "flush_outbound".to_string(),
+            DetailsBuilder::new()
+                .insert("origin", DetailValue::U64(q.origin as u64))
+                .insert("nonce", DetailValue::U64(q.nonce as u64))
+                .insert("destination", DetailValue::U64(chain_id as u64))
+                .insert("msg_hash", DetailValue::Text(q.message_hash.clone()))
+                .insert("result", DetailValue::Text(match &res {
+                    Ok(o) => o.clone(),
+                    Err(e) => e.clone(),
+                }))
+        );
+    }
+    match res {
+        Ok(txhash) => GAS_LEDGER.with(|g| {
+            let mut g = g.borrow_mut();
+            for q in batch.iter() {
+                g.add_pending(txhash.clone(), chain_id, q, batch_size);
+            }
+        }),
+        Err(e) => {
+            add_log(format!("flush outbound to chain {} failed: {}", chain_id, e));
+            OUTBOUND.with(|o| o.borrow_mut().requeue(chain_id, batch));
+        }
+    }
+    OUTBOUND.with(|o| o.borrow_mut().finish_batch(chain_id));
+}
+
+// flush the queues that are full or waited long enough
+async fn flush_due() {
+    let due = OUTBOUND.with(|o| o.borrow().due(get_time()));
+    for chain_id in due {
+        flush_outbound(chain_id).await;
+    }
+}
+
+// queue messages to evm chains and send them in batches, max_batch <= 1 sends each message right away
+#[update(name = "set_outbound_config", guard = "is_admin")]
+#[candid_method(update, rename = "set_outbound_config")]
+fn set_outbound_config(config: OutboundConfig) -> Result<bool, String> {
+    OUTBOUND.with(|o| o.borrow_mut().set_config(config));
+    add_record(
+        ic_cdk::caller(), 
+        "set_outbound_config".to_string(), 
+        DetailsBuilder::new()
+            .insert("max_batch", DetailValue::U64(config.max_batch as u64))
+            .insert("max_delay", DetailValue::U64(config.max_delay))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_outbound_config")]
+#[candid_method(query, rename = "get_outbound_config")]
+fn get_outbound_config() -> OutboundConfig {
+    OUTBOUND.with(|o| o.borrow().config)
+}
+
+// number of queued messages by destination chain
+#[query(name = "get_outbound_sizes")]
+#[candid_method(query, rename = "get_outbound_sizes")]
+fn get_outbound_sizes() -> Vec<(u32, u64)> {
+    OUTBOUND.with(|o| o.borrow().sizes())
+}
+
+// reply the handle_message result of `app` to the sender of `m`, best effort:
+// a failed ack is recorded but does not change the delivery result
+async fn send_ack(m: &Message, leaf: H256, app: Principal, res: &Result<String, String>) {
+    send_reply(m, leaf, principal_to_h256(&app), res, app, "send_ack").await;
+}
+
+// send `res` about `m` (with `leaf`) back to its sender as an ack message from `from`
+async fn send_reply(m: &Message, leaf: H256, from: H256, res: &Result<String, String>, caller: Principal, operation: &str) {
+    let ack = CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        check_destination(&chains, m.origin)?;
+        let c = chains.get_mut(&m.origin).ok_or("dst chain id not exist".to_string())?;
+        let nonce = c.next_outgoing_nonce();
+        Ok(Ack::new(leaf, res).to_message(m, from, nonce))
+    });
+    let ack_res = match ack {
+        Ok(ack) => deliver_to_chain(None, &ack, ack.to_raw()).await,
+        Err(e) => Err(e),
+    };
+    add_record(
+        caller,
+        operation.to_string(),
+        DetailsBuilder::new()
+            .insert("origin", DetailValue::U64(m.origin as u64))
+            .insert("nonce", DetailValue::U64(m.nonce as u64))
+            .insert("msg_hash", DetailValue::Text(format!("{:?}", leaf)))
+            .insert("result", DetailValue::Text(match ack_res {
+                Ok(o) => o,
+                Err(e) => e,
+            }))
+    );
+}
+
+// Some(reason) if the message is past its deadline, Err if the origin height is unknown
+async fn check_deadline(m: &Message) -> Result<Option<String>, String> {
+    let deadline = match m.deadline() {
+        Ok(Some(d)) => d,
+        Ok(None) => return Ok(None),
+        Err(e) => return Ok(Some(format!("invalid deadline: {:?}", e))),
+    };
+    match deadline {
+        Deadline::Timestamp(t) => {
+            let now = ic_cdk::api::time() / 1_000_000_000;
+            Ok((now > t).then(|| format!("expired at {}, now {}", t, now)))
+        }
+        Deadline::OriginBlock(b) => {
+            let gateway = CHAINS.with(|c| {
+                c.borrow().get(&m.origin).map(|c| c.config.gateway_addr).ok_or("src chain id not exist".to_string())
+            })?;
+            let (height, ): (u64, ) = ic_cdk::call(gateway, "get_block_height", ()).await
+                .map_err(|(_code, msg)| msg)?;
+            Ok((height > b).then(|| format!("expired at block {}, origin block {}", b, height)))
+        }
+    }
+}
+
+// record an expired message, and notify the sender if it asked for it
+async fn expire_message(relayer: Principal, m: &Message, payer: Option<FeePayer>, reason: String) -> String {
+    let e = format!("expired: {}", reason);
+    add_log(format!("message {} {}", m, e));
+    record_message(relayer, m, payer, &Err(e.clone()));
+    if m.notify_expiry() && m.origin != IC_CHAIN_ID {
+        send_reply(m, m.to_leaf(), m.recipient, &Err(e.clone()), relayer, "send_expiry").await;
+    }
+    e
+}
+
+// per recipient limit of an ic app, None for the default
+fn app_rate_limit(m: &Message) -> Option<RateLimit> {
+    if m.destination != 0 || m.is_batch() {
+        return None;
+    }
+    let recipient = recipient_principal(&m.recipient).ok()?;
+    APPS.with(|a| a.borrow().rate_limit(&recipient))
+}
+
+// deliver the deferred messages that are within the rate limits now
+async fn drain_deferred() {
+    let ready = LIMITER.with(|l| {
+        l.borrow_mut().take_ready(ic_cdk::api::time(), |d| {
+            Message::from_raw(d.message.clone()).ok().and_then(|m| app_rate_limit(&m))
+        })
+    });
+    for d in ready {
+        // parsed before it was deferred
+        let m = Message::from_raw(d.message.clone()).expect("deferred message invalid");
+        // may have expired while deferred, deliver it if the origin height is unknown
+        match check_deadline(&m).await {
+            Ok(Some(reason)) => {
+                expire_message(d.relayer, &m, Some(d.payer), reason).await;
+                LIMITER.with(|l| l.borrow_mut().finish_inflight(&d));
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => add_log(format!("check deadline of {} failed: {}", m, e)),
+        }
+        let res = deliver(d.relayer, &m, d.message.clone()).await;
+        if is_delivered(&m, &res) {
+            reward_delivery(d.relayer, &m);
+        }
+        record_message(d.relayer, &m, Some(d.payer), &res);
+        // drains overlap when deliveries outlast the period, only this message's keys are released
+        LIMITER.with(|l| l.borrow_mut().finish_inflight(&d));
+    }
+}
+
+// payloads of registered schemas are shown decoded in the message records, returns the type tag
+#[update(name = "register_schema", guard = "is_operator")]
+#[candid_method(update, rename = "register_schema")]
+fn register_schema(schema: PayloadSchema) -> Result<Vec<u8>, String> {
+    let tag = SCHEMAS.with(|s| s.borrow_mut().register(schema.clone()))?;
+    add_record(
+        ic_cdk::caller(), 
+        "register_schema".to_string(), 
+        DetailsBuilder::new()
+            .insert("name", DetailValue::Text(schema.name))
+            .insert("tag", DetailValue::Text(hex::encode(tag)))
+    );
+    Ok(tag.to_vec())
+}
+
+#[update(name = "unregister_schema", guard = "is_operator")]
+#[candid_method(update, rename = "unregister_schema")]
+fn unregister_schema(tag: Vec<u8>) -> Result<bool, String> {
+    SCHEMAS.with(|s| s.borrow_mut().unregister(&tag)).ok_or("schema not found".to_string())?;
+    add_record(
+        ic_cdk::caller(), 
+        "unregister_schema".to_string(), 
+        DetailsBuilder::new()
+            .insert("tag", DetailValue::Text(hex::encode(&tag)))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_schemas")]
+#[candid_method(query, rename = "get_schemas")]
+fn get_schemas() -> Vec<(Vec<u8>, PayloadSchema)> {
+    SCHEMAS.with(|s| s.borrow().schemas.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+}
+
+// default token bucket limits, by (origin, sender) and by recipient, None for no limit
+#[update(name = "set_rate_limits", guard = "is_admin")]
+#[candid_method(update, rename = "set_rate_limits")]
+fn set_rate_limits(sender: Option<RateLimit>, recipient: Option<RateLimit>) -> Result<bool, String> {
+    LIMITER.with(|l| l.borrow_mut().set_defaults(sender, recipient));
+    add_record(
+        ic_cdk::caller(), 
+        "set_rate_limits".to_string(), 
+        DetailsBuilder::new()
+            .insert("sender", DetailValue::Text(format!("{:?}", sender.map(|l| (l.capacity, l.refill_per_sec)))))
+            .insert("recipient", DetailValue::Text(format!("{:?}", recipient.map(|l| (l.capacity, l.refill_per_sec)))))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_rate_limits")]
+#[candid_method(query, rename = "get_rate_limits")]
+fn get_rate_limits() -> (Option<RateLimit>, Option<RateLimit>) {
+    LIMITER.with(|l| {
+        let l = l.borrow();
+        (l.default_sender, l.default_recipient)
+    })
+}
+
+#[query(name = "get_deferred_size")]
+#[candid_method(query, rename = "get_deferred_size")]
+fn get_deferred_size() -> usize {
+    LIMITER.with(|l| l.borrow().deferred.len())
+}
+
+fn bump_index(chain_id: u32) {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let c = chains.get_mut(&chain_id).expect("chain not found");
+        c.bump_index();
+    });
+}
+
+fn record_message(relayer: Principal, m: &Message, payer: Option<FeePayer>, res: &Result<String, String>) {
+    let mut details = DetailsBuilder::new()
+        .insert("origin", DetailValue::U64(m.origin as u64))
+        .insert("send", DetailValue::Text(m.sender.to_string()))
+        .insert("nonce", DetailValue::U64(m.nonce as u64))
+        .insert("destination", DetailValue::U64(m.destination as u64))
+        .insert("recipient", DetailValue::Text(m.recipient.to_string()));
+    if let Some(payer) = payer {
+        details = details.insert("fee_payer", DetailValue::Text(format!("{:?}", payer)));
+    }
+    if let Some(payload) = SCHEMAS.with(|s| s.borrow().format(&m.body)) {
+        details = details.insert("payload", DetailValue::Text(payload));
+    }
+    details = details.insert("result", DetailValue::Text(
+        match res.clone() {
+            Ok(o) => {
+                o
+            }
+            Err(e) => {
+                e
+            }
+        }
+    ));
+    add_record(relayer, "process_message".to_string(), details);
+}
+
+fn record_entry(relayer: Principal, m: &Message, index: usize, entry: &BatchEntry, res: &Result<String, String>) {
+    let mut details = DetailsBuilder::new()
+        .insert("origin", DetailValue::U64(m.origin as u64))
+        .insert("send", DetailValue::Text(m.sender.to_string()))
+        .insert("nonce", DetailValue::U64(m.nonce as u64))
+        .insert("destination", DetailValue::U64(m.destination as u64))
+        .insert("entry", DetailValue::U64(index as u64))
+        .insert("recipient", DetailValue::Text(entry.recipient.to_string()));
+    if let Some(payload) = SCHEMAS.with(|s| s.borrow().format(&entry.payload)) {
+        details = details.insert("payload", DetailValue::Text(payload));
+    }
+    details = details.insert("result", DetailValue::Text(
+        match res.clone() {
+            Ok(o) => o,
+            Err(e) => e,
+        }
+    ));
+    add_record(relayer, "process_batch_entry".to_string(), details);
+}
+
+// an ic app opts in to filtering, called by the app itself or an admin,
+// the app must be built against the same omnic_app::INTERFACE_VERSION
+#[update(name = "register_app")]
+#[candid_method(update, rename = "register_app")]
+async fn register_app(app: Principal, config: AppConfig) -> Result<bool, String> {
+    check_app_owner(app)?;
+    let version = get_app_version(app).await?;
+    if version != INTERFACE_VERSION {
+        return Err(format!("interface version mismatch: proxy {}, app {}", INTERFACE_VERSION, version));
+    }
+    APPS.with(|a| a.borrow_mut().register(app, config.clone()))?;
+    add_record(
+        ic_cdk::caller(), 
+        "register_app".to_string(), 
+        DetailsBuilder::new()
+            .insert("app", DetailValue::Principal(app))
+            .insert("allowed_origins", DetailValue::Text(format!("{:?}", config.allowed_origins)))
+            .insert("allowed_senders", DetailValue::Text(
+                config.allowed_senders.iter().map(|s| hex::encode(s)).collect::<Vec<String>>().join(",")
+            ))
+            .insert("max_payload", DetailValue::U64(config.max_payload))
+    );
+    Ok(true)
+}
+
+#[update(name = "unregister_app")]
+#[candid_method(update, rename = "unregister_app")]
+fn unregister_app(app: Principal) -> Result<bool, String> {
+    check_app_owner(app)?;
+    if !APPS.with(|a| a.borrow_mut().unregister(&app)) {
+        return Err("app not registered".into());
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "unregister_app".to_string(), 
+        DetailsBuilder::new()
+            .insert("app", DetailValue::Principal(app))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_app")]
+#[candid_method(query, rename = "get_app")]
+fn get_app(app: Principal) -> Option<AppConfig> {
+    APPS.with(|a| a.borrow().get(&app))
+}
+
+#[update(name = "grant_role", guard = "is_admin")]
+#[candid_method(update, rename = "grant_role")]
+fn grant_role(user: Principal, role: Role) -> Result<bool, String> {
+    STATE_INFO.with(|s| {
+        s.borrow_mut().grant_role(user, role);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "grant_role".to_string(), 
+        DetailsBuilder::new()
+            .insert("user", DetailValue::Principal(user))
+            .insert("role", DetailValue::Text(format!("{:?}", role)))
+    );
+    Ok(true)
+}
+
+#[update(name = "revoke_role", guard = "is_admin")]
+#[candid_method(update, rename = "revoke_role")]
+fn revoke_role(user: Principal, role: Role) -> Result<bool, String> {
+    STATE_INFO.with(|s| {
+        let mut s = s.borrow_mut();
+        if role == Role::Admin && s.has_role(user, Role::Admin) && s.admin_count() == 1 {
+            return Err("can not revoke the last admin".to_string());
+        }
+        if !s.revoke_role(user, role) {
+            return Err("user does not have this role".to_string());
+        }
+        Ok(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "revoke_role".to_string(), 
+        DetailsBuilder::new()
+            .insert("user", DetailValue::Principal(user))
+            .insert("role", DetailValue::Text(format!("{:?}", role)))
+    );
+    Ok(true)
+}
+
+// only relayers with the relayer role can submit messages when enabled
+#[update(name = "set_relayer_allowlist", guard = "is_admin")]
+#[candid_method(update, rename = "set_relayer_allowlist")]
+fn set_relayer_allowlist(enabled: bool) -> Result<bool, String> {
+    RELAYERS.with(|r| {
+        r.borrow_mut().set_allowlist_enabled(enabled);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_relayer_allowlist".to_string(), 
+        DetailsBuilder::new()
+            .insert("enabled", if enabled { DetailValue::True } else { DetailValue::False })
+    );
+    Ok(true)
+}
+
+#[update(name = "ban_relayer", guard = "is_operator")]
+#[candid_method(update, rename = "ban_relayer")]
+fn ban_relayer(relayer: Principal) -> Result<bool, String> {
+    RELAYERS.with(|r| {
+        r.borrow_mut().ban(relayer);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "ban_relayer".to_string(), 
+        DetailsBuilder::new()
+            .insert("relayer", DetailValue::Principal(relayer))
+    );
+    Ok(true)
+}
+
+#[update(name = "unban_relayer", guard = "is_operator")]
+#[candid_method(update, rename = "unban_relayer")]
+fn unban_relayer(relayer: Principal) -> Result<bool, String> {
+    if !RELAYERS.with(|r| r.borrow_mut().unban(relayer)) {
+        return Err("relayer is not banned".to_string());
+    }
+    add_record(
+        ic_cdk::caller(), 
+        "unban_relayer".to_string(), 
+        DetailsBuilder::new()
+            .insert("relayer", DetailValue::Principal(relayer))
+    );
+    Ok(true)
+}
+
+// reward for each message relayed from `chain_id` and delivered, native reward is paid from the omnic contract's fees on that chain,
+// in total at most the fees of the delivered messages, see set_fee_schedule
+#[update(name = "set_relayer_reward", guard = "is_admin")]
+#[candid_method(update, rename = "set_relayer_reward")]
+fn set_relayer_reward(chain_id: u32, cycles: u64, native: u128) -> Result<bool, String> {
+    RELAYERS.with(|r| {
+        r.borrow_mut().set_reward_config(chain_id, RewardConfig { cycles, native });
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_relayer_reward".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("cycles", DetailValue::U64(cycles))
+            .insert("native", DetailValue::Text(native.to_string()))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_relayer_rewards")]
+#[candid_method(query, rename = "get_relayer_rewards")]
+fn get_relayer_rewards(relayer: Principal) -> RelayerRewards {
+    RELAYERS.with(|r| {
+        r.borrow().get_rewards(&relayer)
+    })
+}
+
+// send all unclaimed cycles to the caller, caller must be a canister
+#[update(name = "claim_cycles_reward")]
+#[candid_method(update, rename = "claim_cycles_reward")]
+async fn claim_cycles_reward() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let amount = RELAYERS.with(|r| r.borrow_mut().take_cycles(caller));
+    if amount == 0 {
+        return Err("no cycles reward to claim".into());
+    }
+    match deposit_cycles(CanisterIdRecord { canister_id: caller }, amount as u128).await {
+        Ok(_) => {
+            add_record(
+                caller, 
+                "claim_cycles_reward".to_string(), 
+                DetailsBuilder::new()
+                    .insert("cycles", DetailValue::U64(amount))
+            );
+            Ok(amount)
+        }
+        Err((_code, msg)) => {
+            RELAYERS.with(|r| r.borrow_mut().refund_cycles(caller, amount));
+            Err(format!("deposit cycles failed: {}", msg))
+        }
+    }
+}
+
+// withdraw all unclaimed native rewards of `chain_id` to the evm address `to`,
+// proxy signs a withdrawNativeFee call to the omnic contract on that chain
+#[update(name = "claim_native_reward")]
+#[candid_method(update, rename = "claim_native_reward")]
+async fn claim_native_reward(chain_id: u32, to: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if ROTATION.with(|r| r.borrow().is_rotating(chain_id)) {
+        return Err(format!("chain {} signer is rotating", chain_id));
+    }
+    let (canister_addr, omnic_addr, rpc, (key_name, derivation_path)) = CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let c = chains.get(&chain_id).ok_or("Chain id not exist".to_string())?;
+        Ok::<(String, String, String, (String, Vec<Vec<u8>>)), String>((c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone(), get_signer(c)))
+    })?;
+    if canister_addr == "" || omnic_addr == "" {
+        return Err("caller address is empty".into());
+    }
+    let client = EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL)
+        .map_err(|e| format!("init client failed: {:?}", e))?
+        .with_signer(key_name, derivation_path);
+    let amount = RELAYERS.with(|r| r.borrow_mut().take_native(caller, chain_id));
+    if amount == 0 {
+        return Err("no native reward to claim".into());
+    }
+    match client.withdraw_native_fee(canister_addr, chain_id, to.clone(), amount).await {
+        Ok(txhash) => {
+            add_record(
+                caller, 
+                "claim_native_reward".to_string(), 
+                DetailsBuilder::new()
+                    .insert("chain_id", DetailValue::U64(chain_id as u64))
+                    .insert("to", DetailValue::Text(to))
+                    .insert("amount", DetailValue::Text(amount.to_string()))
+                    .insert("txhash", DetailValue::Text(hex::encode(txhash)))
+            );
+            Ok(hex::encode(txhash))
+        }
+        Err(e) => {
+            RELAYERS.with(|r| r.borrow_mut().refund_native(caller, chain_id, amount));
+            Err(format!("withdraw native fee failed: {:?}", e))
+        }
+    }
+}
+
+#[update(name = "set_fee_config", guard = "is_admin")]
+#[candid_method(update, rename = "set_fee_config")]
+fn set_fee_config(config: FeeConfig) -> Result<bool, String> {
+    CYCLES_LEDGER.with(|l| {
+        l.borrow_mut().set_fee_config(config);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_fee_config".to_string(), 
+        DetailsBuilder::new()
+            .insert("ic_base", DetailValue::U64(config.ic_base))
+            .insert("ic_per_byte", DetailValue::U64(config.ic_per_byte))
+            .insert("evm_base", DetailValue::U64(config.evm_base))
+            .insert("evm_per_byte", DetailValue::U64(config.evm_per_byte))
+    );
+    Ok(true)
+}
+
+#[query(name = "get_fee_config")]
+#[candid_method(query, rename = "get_fee_config")]
+fn get_fee_config() -> FeeConfig {
+    CYCLES_LEDGER.with(|l| l.borrow().fee_config)
+}
+
+// cycles needed to deliver a message of `len` bytes to `destination`
+#[query(name = "get_fee")]
+#[candid_method(query, rename = "get_fee")]
+fn get_fee(destination: u32, len: u64) -> u64 {
+    CYCLES_LEDGER.with(|l| l.borrow().fee(destination, len as usize))
+}
+
+// prepay delivery fees for an app canister with the attached cycles
+#[update(name = "deposit_cycles")]
+#[candid_method(update, rename = "deposit_cycles")]
+fn deposit_app_cycles(app: Principal) -> Result<u64, String> {
+    let amount = ic_cdk::api::call::msg_cycles_available();
+    if amount == 0 {
+        return Err("no cycles attached".into());
+    }
+    let accepted = ic_cdk::api::call::msg_cycles_accept(amount);
+    let balance = CYCLES_LEDGER.with(|l| l.borrow_mut().deposit(app, accepted));
+    add_record(
+        ic_cdk::caller(), 
+        "deposit_cycles".to_string(), 
+        DetailsBuilder::new()
+            .insert("app", DetailValue::Principal(app))
+            .insert("amount", DetailValue::U64(accepted))
+    );
+    Ok(balance)
+}
+
+// app canister withdraws its unused balance back to itself
+#[update(name = "withdraw_cycles")]
+#[candid_method(update, rename = "withdraw_cycles")]
+async fn withdraw_app_cycles(amount: u64) -> Result<u64, String> {
+    let app = ic_cdk::caller();
+    let balance = CYCLES_LEDGER.with(|l| l.borrow_mut().withdraw(app, amount))?;
+    match deposit_cycles(CanisterIdRecord { canister_id: app }, amount as u128).await {
+        Ok(_) => {
+            add_record(
+                app, 
+                "withdraw_cycles".to_string(), 
+                DetailsBuilder::new()
+                    .insert("amount", DetailValue::U64(amount))
+            );
+            Ok(balance)
+        }
+        Err((_code, msg)) => {
+            CYCLES_LEDGER.with(|l| l.borrow_mut().deposit(app, amount));
+            Err(format!("deposit cycles failed: {}", msg))
+        }
+    }
+}
+
+#[query(name = "get_cycles_balance")]
+#[candid_method(query, rename = "get_cycles_balance")]
+fn get_cycles_balance(app: Principal) -> u64 {
+    CYCLES_LEDGER.with(|l| l.borrow().balance(&app))
+}
+
+// mirror the native fee of OmnicFeeManager on `chain_id`, used to estimate fees paid at the source
+#[update(name = "set_fee_schedule", guard = "is_admin")]
+#[candid_method(update, rename = "set_fee_schedule")]
+fn set_fee_schedule(chain_id: u32, base: u128, per_byte: u128) -> Result<bool, String> {
+    GAS_LEDGER.with(|g| {
+        g.borrow_mut().set_fee_schedule(chain_id, FeeSchedule { base, per_byte });
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_fee_schedule".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("base", DetailValue::Text(base.to_string()))
+            .insert("per_byte", DetailValue::Text(per_byte.to_string()))
+    );
+    Ok(true)
+}
+
+// price oracle: value of 1 native token of `chain_id` in the report unit, pushed by an operator
+#[update(name = "set_token_price", guard = "is_operator")]
+#[candid_method(update, rename = "set_token_price")]
+fn set_token_price(chain_id: u32, price: u128) -> Result<bool, String> {
+    GAS_LEDGER.with(|g| {
+        g.borrow_mut().set_price(chain_id, price);
+    });
+    add_record(
+        ic_cdk::caller(), 
+        "set_token_price".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("price", DetailValue::Text(price.to_string()))
+    );
+    Ok(true)
+}
+
+// alert when the proxy's balance on `chain_id` is below `low_water` wei,
+// hold deliveries to it when below `gas_reserve` wei
+#[update(name = "set_balance_thresholds", guard = "is_admin")]
+#[candid_method(update, rename = "set_balance_thresholds")]
+fn set_balance_thresholds(chain_id: u32, low_water: u128, gas_reserve: u128) -> Result<bool, String> {
+    CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        let c = chains.get_mut(&chain_id).ok_or("Chain id not exist".to_string())?;
+        c.set_balance_thresholds(low_water, gas_reserve);
+        Ok::<(), String>(())
+    })?;
+    add_record(
+        ic_cdk::caller(), 
+        "set_balance_thresholds".to_string(), 
+        DetailsBuilder::new()
+            .insert("chain_id", DetailValue::U64(chain_id as u64))
+            .insert("low_water", DetailValue::Text(low_water.to_string()))
+            .insert("gas_reserve", DetailValue::Text(gas_reserve.to_string()))
+    );
+    Ok(true)
+}
+
+// last checked balances of the proxy's evm addresses
+#[query(name = "get_canister_balances")]
+#[candid_method(query, rename = "get_canister_balances")]
+fn get_canister_balances() -> Vec<CanisterBalance> {
+    CHAINS.with(|chains| {
+        chains.borrow().values()
+            .filter(|c| matches!(c.chain_type(), ChainType::Evm))
+            .map(|c| c.canister_balance())
+            .collect()
+    })
+}
+
+#[query(name = "get_pnl_report", guard = "is_auditor")]
+#[candid_method(query, rename = "get_pnl_report")]
+fn get_pnl_report() -> Vec<PnLReport> {
+    GAS_LEDGER.with(|g| g.borrow().reports())
+}
+
+#[query(name = "get_balance_alerts", guard = "is_auditor")]
+#[candid_method(query, rename = "get_balance_alerts")]
+fn get_balance_alerts() -> Vec<BalanceAlert> {
+    GAS_LEDGER.with(|g| g.borrow().alerts.iter().cloned().collect())
+}
+
+// get stats of one relayer, or all relayers if not set
+#[query(name = "get_relayer_stats", guard = "is_auditor")]
+#[candid_method(query, rename = "get_relayer_stats")]
+fn get_relayer_stats(relayer: Option<Principal>) -> Vec<(Principal, RelayerStats)> {
+    RELAYERS.with(|r| {
+        let r = r.borrow();
+        match relayer {
+            Some(p) => vec![(p, r.get_stats(&p))],
+            None => r.all_stats(),
+        }
+    })
+}
+
+#[query(name = "get_roles")]
+#[candid_method(query, rename = "get_roles")]
+fn get_roles(user: Principal) -> Vec<Role> {
+    STATE_INFO.with(|s| {
+        s.borrow().get_roles(user)
+    })
+}
+
+#[query(name = "get_record_size", guard = "is_auditor")]
+#[candid_method(query, rename = "get_record_size")]
+fn get_record_size(operation: Option<String>) -> usize {
+    RECORDS.with(|r| {
+        let records = r.borrow();
+        records.size(operation)
+    })
+}
+
+#[query(name = "get_record", guard = "is_auditor")]
 #[candid_method(query, rename = "get_record")]
 fn get_record(id: usize) -> Option<Record> {
     RECORDS.with(|r| {
@@ -517,7 +2014,7 @@ fn get_record(id: usize) -> Option<Record> {
     })
 }
 
-#[query(name = "get_records", guard = "is_authorized")]
+#[query(name = "get_records", guard = "is_auditor")]
 #[candid_method(query, rename = "get_records")]
 fn get_records(range: Option<(usize, usize)>, operation: Option<String>) -> Vec<Record> {
     RECORDS.with(|r| {
@@ -550,6 +2047,151 @@ fn get_records(range: Option<(usize, usize)>, operation: Option<String>) -> Vec<
     })
 }
 
+// fetch receipts of the pending deliveries, settle the gas spent against the source fees
+async fn sync_receipts() {
+    let pending = GAS_LEDGER.with(|g| g.borrow_mut().take_pending());
+    // messages sent in one batch share the receipt
+    let mut receipts: HashMap<String, DeliveryReceipt> = HashMap::new();
+    for delivery in pending {
+        let chain = CHAINS.with(|chains| {
+            chains.borrow().get(&delivery.destination).map(|c| (c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone()))
+        });
+        let (omnic_addr, rpc) = match chain {
+            Some(v) => v,
+            None => continue, // chain deleted
+        };
+        let res = match receipts.get(&delivery.txhash) {
+            Some(r) => Ok(Some(r.clone())),
+            None => match EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL) {
+                Ok(client) => client.get_tx_receipt(delivery.txhash.clone()).await.map_err(|e| format!("{:?}", e)),
+                Err(e) => Err(format!("init client failed: {:?}", e)),
+            },
+        };
+        match res {
+            Ok(Some(receipt)) => {
+                GAS_LEDGER.with(|g| g.borrow_mut().settle(&delivery, receipt.gas_used, receipt.gas_price));
+                // a reverted tx leaves no log, a failed message does not revert the rest of its batch
+                let success = receipt.success(&delivery.message_hash);
+                record_delivery(delivery.destination, success);
+                if let (true, Some(relayer)) = (success, delivery.relayer) {
+                    credit_delivery(relayer, delivery.origin, delivery.fee_paid);
+                }
+                add_record(
+                    ic_cdk::id(), 
+                    "delivery_result".to_string(), 
+                    DetailsBuilder::new()
+                        .insert("destination", DetailValue::U64(delivery.destination as u64))
+                        .insert("txhash", DetailValue::Text(delivery.txhash.clone()))
+                        .insert("msg_hash", DetailValue::Text(delivery.message_hash.clone()))
+                        .insert("success", DetailValue::Text(success.to_string()))
+                );
+                for (index, success) in receipt.entry_results(&delivery.message_hash) {
+                    add_record(
+                        ic_cdk::id(), 
+                        "batch_entry_result".to_string(), 
+                        DetailsBuilder::new()
+                            .insert("destination", DetailValue::U64(delivery.destination as u64))
+                            .insert("txhash", DetailValue::Text(delivery.txhash.clone()))
+                            .insert("msg_hash", DetailValue::Text(delivery.message_hash.clone()))
+                            .insert("entry", DetailValue::U64(index))
+                            .insert("success", DetailValue::Text(success.to_string()))
+                    );
+                }
+                receipts.insert(delivery.txhash.clone(), receipt);
+            }
+            Ok(None) | Err(_) => {
+                if let Err(e) = &res {
+                    add_log(format!("get receipt of {} failed: {}", delivery.txhash, e));
+                }
+                let kept = GAS_LEDGER.with(|g| g.borrow_mut().retry_pending(delivery.clone()));
+                if !kept {
+                    add_record(
+                        ic_cdk::id(), 
+                        "receipt_missing".to_string(), 
+                        DetailsBuilder::new()
+                            .insert("destination", DetailValue::U64(delivery.destination as u64))
+                            .insert("txhash", DetailValue::Text(delivery.txhash.clone()))
+                    );
+                }
+            }
+        }
+    }
+}
+
+// check the balances of the addresses the proxy signs with, alert below the low water mark
+// and hold deliveries to chains below the gas reserve
+async fn check_balances() {
+    let chains: Vec<(u32, String, String, String)> = CHAINS.with(|chains| {
+        chains.borrow().values()
+            .filter(|c| matches!(c.chain_type(), ChainType::Evm) && c.canister_addr != "")
+            .map(|c| (c.config.chain_id, c.canister_addr.clone(), c.config.omnic_addr.clone(), c.config.rpc_urls[0].clone()))
+            .collect()
+    });
+    for (chain_id, canister_addr, omnic_addr, rpc) in chains {
+        let balance = match EVMChainClient::new(get_endpoint(&rpc), omnic_addr, MAX_RESP_BYTES, CYCLES_PER_CALL) {
+            Ok(client) => client.get_balance(canister_addr).await.map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("init client failed: {:?}", e)),
+        };
+        let balance = match balance {
+            Ok(b) => b,
+            Err(e) => {
+                add_log(format!("get balance on chain {} failed: {}", chain_id, e));
+                continue;
+            }
+        };
+        let updated = CHAINS.with(|chains| {
+            let mut chains = chains.borrow_mut();
+            chains.get_mut(&chain_id).map(|c| (c.update_balance(balance), c.balance_low, c.low_water))
+        });
+        let (changed, balance_low, low_water) = match updated {
+            Some(v) => v,
+            None => continue, // chain deleted
+        };
+        if changed {
+            add_record(
+                ic_cdk::id(), 
+                if balance_low { "balance_below_reserve" } else { "balance_restored" }.to_string(), 
+                DetailsBuilder::new()
+                    .insert("chain_id", DetailValue::U64(chain_id as u64))
+                    .insert("balance", DetailValue::Text(balance.to_string()))
+            );
+        }
+        let alert = GAS_LEDGER.with(|g| g.borrow_mut().check_balance(chain_id, balance, low_water, get_time()));
+        if let Some(alert) = alert {
+            add_log(format!("balance on chain {} is low: {} < {}", chain_id, alert.balance, alert.threshold));
+            add_record(
+                ic_cdk::id(), 
+                "low_balance_alert".to_string(), 
+                DetailsBuilder::new()
+                    .insert("chain_id", DetailValue::U64(chain_id as u64))
+                    .insert("balance", DetailValue::Text(alert.balance.to_string()))
+                    .insert("threshold", DetailValue::Text(alert.threshold.to_string()))
+            );
+        }
+    }
+}
+
+#[heartbeat]
+fn heart_beat() {
+    for task in cron_ready_tasks() {
+        let kind = task.get_payload::<Task>().expect("Serialization error");
+        match kind {
+            Task::SyncReceipts => {
+                ic_cdk::spawn(sync_receipts());
+            },
+            Task::CheckBalances => {
+                ic_cdk::spawn(check_balances());
+            },
+            Task::DrainDeferred => {
+                ic_cdk::spawn(drain_deferred());
+            },
+            Task::FlushOutbound => {
+                ic_cdk::spawn(flush_due());
+            },
+        }
+    }
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     let chains = CHAINS.with(|c| {
@@ -561,18 +2203,104 @@ fn pre_upgrade() {
     let records = RECORDS.with(|r| {
         r.replace(RecordDB::new())
     });
-    ic_cdk::storage::stable_save((chains, state_info, records, )).expect("pre upgrade error");
+    let rpc_secrets = RPC_SECRETS.with(|s| {
+        s.replace(RpcSecrets::new())
+    });
+    let relayers = RELAYERS.with(|r| {
+        r.replace(RelayerDB::new())
+    });
+    let cycles_ledger = CYCLES_LEDGER.with(|l| {
+        l.replace(CyclesLedger::new())
+    });
+    let gas_ledger = GAS_LEDGER.with(|g| {
+        g.replace(GasLedger::new())
+    });
+    let rotation = ROTATION.with(|r| {
+        r.replace(Rotation::new())
+    });
+    let apps = APPS.with(|a| {
+        a.replace(AppRegistry::new())
+    });
+    let limiter = LIMITER.with(|l| {
+        l.replace(RateLimiter::new())
+    });
+    let schemas = SCHEMAS.with(|s| {
+        s.replace(SchemaRegistry::new())
+    });
+    let outbound = OUTBOUND.with(|o| {
+        o.replace(OutboundQueue::new())
+    });
+    // post_upgrade restores this layout, or the baseline one of restore_v0
+    ic_cdk::storage::stable_save((chains, state_info, records, rpc_secrets, relayers, cycles_ledger, gas_ledger, _take_cron_state(), rotation, apps, limiter, schemas, outbound, )).expect("pre upgrade error");
+}
+
+// stable layout of the proxy before roles and per chain state: chains, owners, records,
+// restored when the current layout does not decode
+fn restore_v0(current_err: String) -> StableState {
+    let (chains, state_info, records): (HashMap<u32, ChainStateV0>, StateInfoV0, RecordDB) =
+        ic_cdk::storage::stable_restore()
+            .unwrap_or_else(|e| panic!("post upgrade error: {}, baseline layout: {}", current_err, e));
+    // keep signing with the address set on the omnic contracts
+    let canister = ic_cdk::id();
+    let chains = chains.into_iter().map(|(id, c)| {
+        let mut chain: ChainState = c.into();
+        chain.derivation_path = legacy_signer_path(&canister);
+        (id, chain)
+    }).collect();
+    (
+        chains,
+        state_info.into(),
+        records,
+        RpcSecrets::new(),
+        RelayerDB::new(),
+        CyclesLedger::new(),
+        GasLedger::new(),
+        None,
+        Rotation::new(),
+        AppRegistry::new(),
+        RateLimiter::new(),
+        SchemaRegistry::new(),
+        OutboundQueue::new(),
+    )
 }
 
+type StableState = (
+    HashMap<u32, ChainState>, 
+    StateInfo, 
+    RecordDB,
+    RpcSecrets,
+    RelayerDB,
+    CyclesLedger,
+    GasLedger,
+    Option<TaskScheduler>,
+    Rotation,
+    AppRegistry,
+    RateLimiter,
+    SchemaRegistry,
+    OutboundQueue,
+);
+
 #[post_upgrade]
 fn post_upgrade() {
+    let restored: Result<StableState, String> = ic_cdk::storage::stable_restore();
     let (chains, 
         state_info,
         records,
-    ): (HashMap<u32, ChainState>, 
-        StateInfo, 
-        RecordDB,
-    ) = ic_cdk::storage::stable_restore().expect("post upgrade error");
+        rpc_secrets,
+        relayers,
+        cycles_ledger,
+        gas_ledger,
+        cron_state,
+        rotation,
+        apps,
+        limiter,
+        schemas,
+        outbound,
+    ) = match restored {
+        Ok(state) => state,
+        Err(e) => restore_v0(e),
+    };
+    let schedule = cron_state.is_none();
     
     CHAINS.with(|c| {
         c.replace(chains);
@@ -583,6 +2311,38 @@ fn post_upgrade() {
     RECORDS.with(|r| {
         r.replace(records);
     });
+    RPC_SECRETS.with(|s| {
+        s.replace(rpc_secrets);
+    });
+    RELAYERS.with(|r| {
+        r.replace(relayers);
+    });
+    CYCLES_LEDGER.with(|l| {
+        l.replace(cycles_ledger);
+    });
+    GAS_LEDGER.with(|g| {
+        g.replace(gas_ledger);
+    });
+    _put_cron_state(cron_state);
+    ROTATION.with(|r| {
+        r.replace(rotation);
+    });
+    APPS.with(|a| {
+        a.replace(apps);
+    });
+    LIMITER.with(|l| {
+        l.replace(limiter);
+    });
+    SCHEMAS.with(|s| {
+        s.replace(schemas);
+    });
+    OUTBOUND.with(|o| {
+        o.replace(outbound);
+    });
+    // no tasks were scheduled before the cron state was kept
+    if schedule {
+        schedule_tasks();
+    }
 }
 
 // get the unix timestamp in second
@@ -590,11 +2350,111 @@ fn get_time() -> u64 {
     ic_cdk::api::time() / 1000000000
 }
 
-fn is_authorized() -> Result<(), String> {
+// reject messages from a paused origin or to a paused destination.
+// with outbound queueing a message to an evm chain that can not be sent to now is queued,
+// flush_outbound holds it there until the chain can be sent to, so it does not block its origin
+fn check_route(m: &Message) -> Result<(), String> {
+    CHAINS.with(|chains| {
+        let chains = chains.borrow();
+        let origin = chains.get(&m.origin).ok_or("src chain id not exist".to_string())?;
+        if origin.origin_paused {
+            return Err(format!("origin chain {} is paused", m.origin));
+        }
+        if m.destination != 0 {
+            if OUTBOUND.with(|o| o.borrow().enabled()) {
+                chains.get(&m.destination).ok_or("dst chain id not exist".to_string())?;
+            } else {
+                check_destination(&chains, m.destination)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// whether the proxy can send txs to the evm chain now
+fn check_destination(chains: &HashMap<u32, ChainState>, destination: u32) -> Result<(), String> {
+    let dst = chains.get(&destination).ok_or("dst chain id not exist".to_string())?;
+    if dst.destination_paused {
+        return Err(format!("destination chain {} is paused", destination));
+    }
+    // not rejected as invalid, the relayer can submit it again once the balance is topped up
+    if dst.balance_low {
+        return Err(format!("destination chain {} balance is below the gas reserve", destination));
+    }
+    if ROTATION.with(|r| r.borrow().is_rotating(destination)) {
+        return Err(format!("destination chain {} signer is rotating", destination));
+    }
+    Ok(())
+}
+
+// update the delivery breaker of the destination chain
+fn record_delivery(chain_id: u32, success: bool) {
+    let threshold = STATE_INFO.with(|s| s.borrow().breaker_threshold);
+    let tripped = CHAINS.with(|chains| {
+        let mut chains = chains.borrow_mut();
+        match chains.get_mut(&chain_id) {
+            Some(c) => c.record_delivery(success, threshold),
+            None => false,
+        }
+    });
+    if tripped {
+        add_log(format!("breaker tripped, destination chain {} paused", chain_id));
+        add_record(
+            ic_cdk::id(), 
+            "breaker_tripped".to_string(), 
+            DetailsBuilder::new()
+                .insert("chain_id", DetailValue::U64(chain_id as u64))
+                .insert("reason", DetailValue::Text("delivery failures".to_string()))
+        );
+    }
+}
+
+// charge the delivery fee from cycles attached by the relayer,
+// or from the prepaid balance of the recipient canister if not enough cycles attached
+fn charge_fee(m: &Message, len: usize) -> Result<FeePayer, String> {
+    let fee = CYCLES_LEDGER.with(|l| l.borrow().fee(m.destination, len));
+    let available = ic_cdk::api::call::msg_cycles_available();
+    if available >= fee {
+        let _accepted = ic_cdk::api::call::msg_cycles_accept(fee);
+        CYCLES_LEDGER.with(|l| l.borrow_mut().add_collected(fee));
+        return Ok(FeePayer::Attached);
+    }
+    // a batch has no single app to charge
+    if m.destination != 0 || m.is_batch() {
+        return Err(format!("Insufficient cycles: require {} cycles. Received {}.", fee, available));
+    }
+    let app = recipient_principal(&m.recipient)?;
+    CYCLES_LEDGER.with(|l| l.borrow_mut().charge(app, fee))?;
+    Ok(FeePayer::App(app))
+}
+
+// banned relayers are always rejected, in allowlist mode the relayer role is required
+fn check_relayer(relayer: Principal) -> Result<(), String> {
+    let (banned, allowlist_enabled) = RELAYERS.with(|r| {
+        let r = r.borrow();
+        (r.is_banned(&relayer), r.allowlist_enabled)
+    });
+    if banned {
+        return Err("relayer is banned".into());
+    }
+    if allowlist_enabled && !STATE_INFO.with(|s| s.borrow().has_role(relayer, Role::Relayer)) {
+        return Err("relayer is not in the allowlist".into());
+    }
+    Ok(())
+}
+
+fn check_app_owner(app: Principal) -> Result<(), String> {
+    if ic_cdk::caller() == app {
+        return Ok(());
+    }
+    check_role(Role::Admin).map_err(|_| "caller is not the app or an admin".to_string())
+}
+
+fn check_role(role: Role) -> Result<(), String> {
     let user = ic_cdk::api::caller();
     STATE_INFO.with(|info| {
         let info = info.borrow();
-        if !info.is_owner(user) {
+        if !info.has_role(user, role) {
             Err("unauthorized!".into())
         } else {
             Ok(())
@@ -602,13 +2462,37 @@ fn is_authorized() -> Result<(), String> {
     })
 }
 
+fn is_admin() -> Result<(), String> {
+    check_role(Role::Admin)
+}
+
+fn is_operator() -> Result<(), String> {
+    check_role(Role::Operator)
+}
+
+fn is_auditor() -> Result<(), String> {
+    check_role(Role::Auditor)
+}
+
+// key name and derivation path the proxy signs with on this chain
+fn get_signer(c: &ChainState) -> (String, Vec<Vec<u8>>) {
+    let key_name = STATE_INFO.with(|s| s.borrow().key_name.clone());
+    (c.signer_key(&key_name), c.signer_path(&ic_cdk::id()))
+}
+
+// resolve the real endpoint of a provider url with its credential
+fn get_endpoint(url: &str) -> RpcEndpoint {
+    RPC_SECRETS.with(|s| s.borrow().resolve(url))
+}
+
+// logs are public, errors may echo a provider url with its api key
 fn add_log(log: String) {
     LOGS.with(|l| {
         let mut logs = l.borrow_mut();
         if logs.len() == 1000 {
             logs.pop_front();
         } 
-        logs.push_back(log);
+        logs.push_back(redact_text(&log));
     });
 }
 
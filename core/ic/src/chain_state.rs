@@ -1,6 +1,6 @@
 use std::collections::VecDeque;
 use ic_web3::types::H256;
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use crate::config::{ChainConfig, ChainType};
 
 #[derive(CandidType, Deserialize, Clone, Default)]
@@ -9,9 +9,66 @@ pub struct ChainState {
     pub roots: VecDeque<Vec<u8>>,
     pub next_index: u32, // leaf_index for next message
     pub canister_addr: String, // the address controlled by the proxy canister on this chain
+    pub origin_paused: bool, // stop processing messages from this chain
+    pub destination_paused: bool, // stop delivering messages to this chain
+    pub delivery_failures: u32, // consecutive delivery failures to this chain
+    pub root_mismatches: u32, // consecutive failed root checks of this chain
+    pub balance: u128, // last known balance of canister_addr on this chain, in wei
+    pub low_water: u128, // alert when the balance is below this
+    pub gas_reserve: u128, // stop delivering to this chain when the balance is below this
+    pub balance_low: bool,
+    pub derivation_path: Vec<Vec<u8>>, // ecdsa derivation path of canister_addr, empty for the default
+    pub key_name: String, // ecdsa key of canister_addr, empty for the proxy's key, set while migrating keys
+    pub outgoing_nonce: u32, // nonce of the next message sent from the ic to this chain, e.g. acks
     // pub txs: Vec<Message>, // outgoging txs
 }
 
+/// the path all chains signed with before each chain had its own signer
+pub fn legacy_signer_path(canister: &Principal) -> Vec<Vec<u8>> {
+    vec![canister.as_slice().to_vec()]
+}
+
+/// ChainState as stored before the pause, balance, signer and nonce fields,
+/// restored when upgrading from that layout
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChainStateV0 {
+    pub config: ChainConfig,
+    pub roots: VecDeque<Vec<u8>>,
+    pub next_index: u32,
+    pub canister_addr: String,
+}
+
+impl From<ChainStateV0> for ChainState {
+    fn from(s: ChainStateV0) -> Self {
+        ChainState {
+            config: s.config,
+            roots: s.roots,
+            next_index: s.next_index,
+            canister_addr: s.canister_addr,
+            ..ChainState::default()
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PauseState {
+    pub origin_paused: bool,
+    pub destination_paused: bool,
+    pub delivery_failures: u32,
+    pub root_mismatches: u32,
+    pub balance_low: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CanisterBalance {
+    pub chain_id: u32,
+    pub canister_addr: String,
+    pub balance: u128,
+    pub low_water: u128,
+    pub gas_reserve: u128,
+    pub balance_low: bool,
+}
+
 impl ChainState {
     pub fn new(
         chain_config: ChainConfig,
@@ -21,9 +78,21 @@ impl ChainState {
             roots: VecDeque::new(),
             next_index: 0,
             canister_addr: "".into(),
+            origin_paused: false,
+            destination_paused: false,
+            delivery_failures: 0,
+            root_mismatches: 0,
+            balance: 0,
+            low_water: 0,
+            gas_reserve: 0,
+            balance_low: false,
+            derivation_path: Vec::new(),
+            key_name: String::new(),
+            outgoing_nonce: 0,
         }
     }
 
+    /// replace the config in place, roots, next_index and canister_addr are kept
     pub fn update_config(&mut self, new_config: ChainConfig) {
         self.config = new_config;
     }
@@ -32,10 +101,26 @@ impl ChainState {
         self.config.add_urls(urls);
     }
 
+    pub fn remove_url(&mut self, url: &str) -> bool {
+        self.config.remove_rpc_url(url)
+    }
+
+    pub fn replace_urls(&mut self, urls: Vec<String>) {
+        self.config.set_rpc_urls(urls);
+    }
+
     pub fn rpc_urls(&self) -> Vec<String> {
         self.config.rpc_urls.clone()
     }
 
+    /// chain state for public queries, rpc urls are redacted
+    pub fn redacted(&self) -> ChainState {
+        ChainState {
+            config: self.config.redacted(),
+            ..self.clone()
+        }
+    }
+
     pub fn chain_type(&self) -> ChainType {
         self.config.chain_type.clone()
     }
@@ -44,6 +129,117 @@ impl ChainState {
         self.canister_addr = addr;
     }
 
+    /// the address has to be derived again after the path changes
+    pub fn set_derivation_path(&mut self, path: Vec<Vec<u8>>) {
+        self.derivation_path = path;
+        self.canister_addr = "".into();
+    }
+
+    pub fn signer_key(&self, default: &str) -> String {
+        if self.key_name.is_empty() {
+            default.to_string()
+        } else {
+            self.key_name.clone()
+        }
+    }
+
+    /// derivation path of the signer on this chain, defaults to [canister id, chain id],
+    /// so each chain has its own address and nonce space.
+    /// chains added before that keep legacy_signer_path as their derivation_path
+    pub fn signer_path(&self, canister: &Principal) -> Vec<Vec<u8>> {
+        if !self.derivation_path.is_empty() {
+            return self.derivation_path.clone();
+        }
+        vec![canister.as_slice().to_vec(), self.config.chain_id.to_be_bytes().to_vec()]
+    }
+
+    pub fn set_paused(&mut self, origin_paused: bool, destination_paused: bool) {
+        self.origin_paused = origin_paused;
+        self.destination_paused = destination_paused;
+        // start counting again after resume
+        if !origin_paused {
+            self.root_mismatches = 0;
+        }
+        if !destination_paused {
+            self.delivery_failures = 0;
+        }
+    }
+
+    pub fn pause_state(&self) -> PauseState {
+        PauseState {
+            origin_paused: self.origin_paused,
+            destination_paused: self.destination_paused,
+            delivery_failures: self.delivery_failures,
+            root_mismatches: self.root_mismatches,
+            balance_low: self.balance_low,
+        }
+    }
+
+    pub fn set_balance_thresholds(&mut self, low_water: u128, gas_reserve: u128) {
+        self.low_water = low_water;
+        self.gas_reserve = gas_reserve;
+        self.balance_low = self.balance < gas_reserve;
+    }
+
+    /// update the balance of canister_addr, deliveries to this chain are held
+    /// while the balance is below the gas reserve, return true if balance_low changed
+    pub fn update_balance(&mut self, balance: u128) -> bool {
+        self.balance = balance;
+        let low = balance < self.gas_reserve;
+        let changed = low != self.balance_low;
+        self.balance_low = low;
+        changed
+    }
+
+    pub fn canister_balance(&self) -> CanisterBalance {
+        CanisterBalance {
+            chain_id: self.config.chain_id,
+            canister_addr: self.canister_addr.clone(),
+            balance: self.balance,
+            low_water: self.low_water,
+            gas_reserve: self.gas_reserve,
+            balance_low: self.balance_low,
+        }
+    }
+
+    /// record a delivery result to this chain, pause the chain as destination
+    /// when failures reach the threshold, return true if the breaker tripped
+    /// threshold 0 disables the breaker
+    pub fn record_delivery(&mut self, success: bool, threshold: u32) -> bool {
+        if success {
+            self.delivery_failures = 0;
+            return false;
+        }
+        self.delivery_failures += 1;
+        if threshold > 0 && self.delivery_failures >= threshold && !self.destination_paused {
+            self.destination_paused = true;
+            return true;
+        }
+        false
+    }
+
+    /// record a root check result of this chain, pause the chain as origin
+    /// when mismatches reach the threshold, return true if the breaker tripped
+    pub fn record_root_check(&mut self, success: bool, threshold: u32) -> bool {
+        if success {
+            self.root_mismatches = 0;
+            return false;
+        }
+        self.root_mismatches += 1;
+        if threshold > 0 && self.root_mismatches >= threshold && !self.origin_paused {
+            self.origin_paused = true;
+            return true;
+        }
+        false
+    }
+
+    /// take the nonce for the next message from the ic to this chain
+    pub fn next_outgoing_nonce(&mut self) -> u32 {
+        let nonce = self.outgoing_nonce;
+        self.outgoing_nonce += 1;
+        nonce
+    }
+
     pub fn bump_index(&mut self) {
         self.next_index += 1;
     }
@@ -76,4 +272,70 @@ impl ChainState {
             H256::from_slice(&r)
         }).collect()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::iter::FromIterator;
+    use crate::state::{RecordDB, Role, StateInfo, StateInfoV0};
+
+    fn chain_v0(chain_id: u32) -> ChainStateV0 {
+        ChainStateV0 {
+            config: ChainConfig {
+                chain_id,
+                rpc_urls: vec!["https://rpc.example".into()],
+                omnic_addr: "0x1".into(),
+                ..ChainConfig::default()
+            },
+            roots: VecDeque::from(vec![vec![1; 32]]),
+            next_index: 7,
+            canister_addr: "0x2".into(),
+        }
+    }
+
+    // the proxy's stable layout before the series of state changes
+    #[test]
+    fn it_restores_the_v0_proxy_layout() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let chains: HashMap<u32, ChainStateV0> = HashMap::from_iter(vec![(5, chain_v0(5))]);
+        let info = StateInfoV0 {
+            owners: HashSet::from_iter(vec![owner]),
+            fetch_root_period: 60,
+            fetch_roots_period: 90,
+            query_rpc_number: 1,
+        };
+        let bytes = candid::encode_args((chains, info, RecordDB::default())).unwrap();
+
+        let current: Result<(HashMap<u32, ChainState>, StateInfo, RecordDB), _> = candid::decode_args(&bytes);
+        assert!(current.is_err());
+
+        let (chains, info, _records): (HashMap<u32, ChainStateV0>, StateInfoV0, RecordDB) =
+            candid::decode_args(&bytes).unwrap();
+        let chain = ChainState::from(chains[&5].clone());
+        let info = StateInfo::from(info);
+        assert_eq!(chain.config.chain_id, 5);
+        assert_eq!(chain.next_index, 7);
+        assert_eq!(chain.canister_addr, "0x2");
+        assert!(chain.is_root_exist(H256::from([1; 32])));
+        assert!(!chain.origin_paused && !chain.destination_paused);
+        assert!(info.has_role(owner, Role::Admin));
+        assert_eq!(info.key_name, crate::consts::LEGACY_KEY_NAME);
+    }
+
+    #[test]
+    fn it_keeps_the_legacy_signer() {
+        let canister = Principal::from_slice(&[3; 10]);
+        let mut chain = ChainState::from(chain_v0(5));
+        chain.derivation_path = legacy_signer_path(&canister);
+        assert_eq!(chain.signer_path(&canister), vec![canister.as_slice().to_vec()]);
+
+        // new chains get a signer of their own
+        let new = ChainState::from(chain_v0(6));
+        assert_eq!(
+            new.signer_path(&canister),
+            vec![canister.as_slice().to_vec(), 6u32.to_be_bytes().to_vec()]
+        );
+    }
+}
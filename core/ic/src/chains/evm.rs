@@ -1,32 +1,59 @@
 use ic_web3::Web3;
-use ic_web3::transports::ICHttp;
+use ic_web3::ethabi::{self, Token};
 use ic_web3::contract::{Contract, Options};
-use ic_web3::types::{U256, H256, Bytes, Address, BlockNumber, BlockId};
+use ic_web3::contract::tokens::Tokenize;
+use ic_web3::types::{U256, H256, Bytes, Address, BlockNumber, BlockId, TransactionId};
 use ic_web3::ic::KeyInfo;
 
 use std::str::FromStr;
+use std::collections::HashMap;
 use async_trait::async_trait;
 
-use crate::consts::KEY_NAME;
+use crate::chains::http::HttpTransport;
+use crate::secret::RpcEndpoint;
 use crate::error::OmnicError;
 use crate::error::OmnicError::*;
 use crate::traits::chain::HomeContract;
+use crate::gas::DeliveryReceipt;
+use crate::utils::keccak256;
+use crate::types::Message;
 
 const OMNIC_ABI: &[u8] = include_bytes!("./omnic.abi");
+// gas limit of a call, and of each handleMessage call in a batch
+const GAS_LIMIT: u64 = 100000;
+
+// gas limit of processing `msg_bytes`, a batch message needs it for each of its entries
+fn message_gas(msg_bytes: &[u8]) -> u64 {
+    let deliveries = Message::from_raw(msg_bytes.to_vec())
+        .map(|m| m.deliveries())
+        .unwrap_or(1);
+    GAS_LIMIT * deliveries.max(1) as u64
+}
+
+// calldata of setOmnicCanisterAddr, for the owner multisig to submit
+pub fn set_omnic_canister_addr_calldata(new_addr: &str) -> Result<Vec<u8>, OmnicError> {
+    let new_addr = Address::from_str(new_addr)
+        .map_err(|e| Other(format!("address decode failed: {:?}", e)))?;
+    let abi = ethabi::Contract::load(OMNIC_ABI)?;
+    let calldata = abi.function("setOmnicCanisterAddr")?
+        .encode_input(&[Token::Address(new_addr)])?;
+    Ok(calldata)
+}
 
 pub struct EVMChainClient {
-    w3: Web3<ICHttp>,
-    contract: Contract<ICHttp>,
+    w3: Web3<HttpTransport>,
+    contract: Contract<HttpTransport>,
+    signer: Option<(String, Vec<Vec<u8>>)>, // ecdsa key name and derivation path
 }
 
 impl EVMChainClient {
     pub fn new(
-        rpc_url: String,
+        endpoint: RpcEndpoint,
         omnic_addr: String,
         max_resp_bytes: Option<u64>,
         cycles_per_call: Option<u64>,
     ) -> Result<EVMChainClient, OmnicError> {
-        let http = ICHttp::new(&rpc_url, max_resp_bytes, cycles_per_call)?;
+        let http = HttpTransport::new(endpoint, max_resp_bytes, cycles_per_call);
         let w3 = Web3::new(http);
         let contract_address = Address::from_str(&omnic_addr)
             .map_err(|e| Other(format!("address decode failed: {:?}", e)))?;
@@ -39,18 +66,24 @@ impl EVMChainClient {
         Ok(EVMChainClient {
             w3: w3,
             contract: contract,
+            signer: None,
         })
     }
-}
 
-#[async_trait]
-impl HomeContract for EVMChainClient {
-    async fn dispatch_message(&self, caller: String, dst_chain: u32, msg_bytes: Vec<u8>) -> Result<H256, OmnicError> {
+    // required for signed calls
+    pub fn with_signer(mut self, key_name: String, derivation_path: Vec<Vec<u8>>) -> Self {
+        self.signer = Some((key_name, derivation_path));
+        self
+    }
+
+    // sign a call to the omnic contract with the canister's ecdsa key and send it
+    async fn send_signed_call<P: Tokenize + Send>(&self, func: &str, params: P, gas: u64, caller: String, chain_id: u32) -> Result<H256, OmnicError> {
         let caller_addr = Address::from_str(&caller)
             .map_err(|e| Other(format!("address decode failed: {:?}", e)))?;
         // ecdsa key info
-        let derivation_path = vec![ic_cdk::id().as_slice().to_vec()];
-        let key_info = KeyInfo{ derivation_path: derivation_path, key_name: KEY_NAME.to_string() };
+        let (key_name, derivation_path) = self.signer.clone()
+            .ok_or(Other("signer not set".to_string()))?;
+        let key_info = KeyInfo{ derivation_path: derivation_path, key_name: key_name };
         // add nonce to options
         let tx_count = self.w3.eth()
             .transaction_count(caller_addr, None)
@@ -63,20 +96,49 @@ impl HomeContract for EVMChainClient {
             .map_err(|e| ClientError(format!("get gas_price error: {}", e)))?;
         // legacy transaction type is still ok
         let options = Options::with(|op| { 
-            op.gas = Some(U256::from(100000));
+            op.gas = Some(U256::from(gas));
             op.nonce = Some(tx_count);
             op.gas_price = Some(gas_price);
         });
         ic_cdk::println!("gas price: {:?}", gas_price);
         let txhash = self.contract
-            .signed_call("processMessage", (msg_bytes,), options, caller, key_info, dst_chain as u64)
+            .signed_call(func, params, options, caller, key_info, chain_id as u64)
             .await
-            .map_err(|e| ClientError(format!("processMessage failed: {}", e)))?;
+            .map_err(|e| ClientError(format!("{} failed: {}", func, e)))?;
 
         ic_cdk::println!("txhash: {}", hex::encode(txhash));
 
         Ok(txhash)
     }
+}
+
+#[async_trait]
+impl HomeContract for EVMChainClient {
+    async fn dispatch_message(&self, caller: String, dst_chain: u32, msg_bytes: Vec<u8>) -> Result<H256, OmnicError> {
+        self.send_signed_call("processMessage", (msg_bytes,), GAS_LIMIT, caller, dst_chain).await
+    }
+
+    async fn dispatch_batch(&self, caller: String, dst_chain: u32, msg_bytes: Vec<u8>) -> Result<H256, OmnicError> {
+        let gas = message_gas(&msg_bytes);
+        self.send_signed_call("processMessages", (msg_bytes,), gas, caller, dst_chain).await
+    }
+
+    async fn dispatch_message_batch(&self, caller: String, dst_chain: u32, msgs: Vec<Vec<u8>>) -> Result<H256, OmnicError> {
+        let gas: u64 = msgs.iter().map(|m| message_gas(m)).sum();
+        self.send_signed_call("processMessageBatch", (msgs,), gas, caller, dst_chain).await
+    }
+
+    async fn set_omnic_canister_addr(&self, caller: String, chain_id: u32, new_addr: String) -> Result<H256, OmnicError> {
+        let new_addr = Address::from_str(&new_addr)
+            .map_err(|e| Other(format!("address decode failed: {:?}", e)))?;
+        self.send_signed_call("setOmnicCanisterAddr", (new_addr,), GAS_LIMIT, caller, chain_id).await
+    }
+
+    async fn withdraw_native_fee(&self, caller: String, chain_id: u32, to: String, amount: u128) -> Result<H256, OmnicError> {
+        let to_addr = Address::from_str(&to)
+            .map_err(|e| Other(format!("address decode failed: {:?}", e)))?;
+        self.send_signed_call("withdrawNativeFee", (to_addr, U256::from(amount)), GAS_LIMIT, caller, chain_id).await
+    }
 
     async fn send_raw_tx(&self, raw_tx: Vec<u8>) -> Result<Vec<u8>, OmnicError> {
         let raw = Bytes::from(raw_tx);
@@ -125,4 +187,77 @@ impl HomeContract for EVMChainClient {
             .map(|v| v.as_u64())
             .map_err(|e| ClientError(format!("get tx count error: {:?}", e)))
     }
+
+    async fn get_tx_receipt(&self, txhash: String) -> Result<Option<DeliveryReceipt>, OmnicError> {
+        let hash = H256::from_str(&txhash).map_err(|e| ClientError(format!("txhash convert failed: {:?}", e)))?;
+        let receipt = match self.w3.eth().transaction_receipt(hash)
+            .await
+            .map_err(|e| ClientError(format!("get tx receipt error: {:?}", e)))? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let gas_used = match receipt.gas_used {
+            Some(v) => v.as_u128(),
+            None => return Ok(None),
+        };
+        // effective_gas_price is missing on pre-london nodes, use the gas price of the tx then
+        let gas_price = match receipt.effective_gas_price {
+            Some(v) => v.as_u128(),
+            None => self.w3.eth().transaction(TransactionId::Hash(hash))
+                .await
+                .map_err(|e| ClientError(format!("get tx error: {:?}", e)))?
+                .and_then(|tx| tx.gas_price)
+                .map(|v| v.as_u128())
+                .unwrap_or_default(),
+        };
+        // ProcessMessage(bytes32 indexed messageHash, bytes indexed returnData, bool success)
+        let topic = H256::from(keccak256(b"ProcessMessage(bytes32,bytes,bool)"));
+        let results = receipt.logs.iter()
+            .filter(|log| log.address == self.contract.address() && log.topics.len() > 1 && log.topics[0] == topic)
+            .map(|log| (hex::encode(log.topics[1]), log.data.0.last().map(|b| *b != 0).unwrap_or_default()))
+            .collect();
+        // ProcessBatchEntry(bytes32 indexed messageHash, uint256 index, bool success)
+        let entry_topic = H256::from(keccak256(b"ProcessBatchEntry(bytes32,uint256,bool)"));
+        let mut entries: HashMap<String, Vec<(u64, bool)>> = HashMap::new();
+        for log in receipt.logs.iter() {
+            if log.address != self.contract.address() || log.topics.len() < 2 || log.topics[0] != entry_topic || log.data.0.len() < 64 {
+                continue;
+            }
+            let index = U256::from_big_endian(&log.data.0[..32]);
+            if index > U256::from(u64::MAX) {
+                continue;
+            }
+            entries.entry(hex::encode(log.topics[1]))
+                .or_default()
+                .push((index.as_u64(), log.data.0[63] != 0));
+        }
+        Ok(Some(DeliveryReceipt { gas_used, gas_price, results, entries }))
+    }
+
+    async fn get_proxy_canister_addr(&self) -> Result<String, OmnicError> {
+        let addr: Address = self.contract
+            .query("omnicProxyCanisterAddr", (), None, Options::default(), None)
+            .await
+            .map_err(|e| ClientError(format!("get proxy canister addr error: {:?}", e)))?;
+        Ok(hex::encode(addr))
+    }
+
+    async fn has_pending_txs(&self, addr: String) -> Result<bool, OmnicError> {
+        let addr = Address::from_str(&addr).map_err(|e| ClientError(format!("address convert faild: {:?}", e)))?;
+        let latest = self.w3.eth().transaction_count(addr, Some(BlockNumber::Latest))
+            .await
+            .map_err(|e| ClientError(format!("get tx count error: {:?}", e)))?;
+        let pending = self.w3.eth().transaction_count(addr, Some(BlockNumber::Pending))
+            .await
+            .map_err(|e| ClientError(format!("get tx count error: {:?}", e)))?;
+        Ok(pending > latest)
+    }
+
+    async fn get_balance(&self, addr: String) -> Result<u128, OmnicError> {
+        let addr = Address::from_str(&addr).map_err(|e| ClientError(format!("address convert faild: {:?}", e)))?;
+        self.w3.eth().balance(addr, None)
+            .await
+            .map(|v| v.as_u128())
+            .map_err(|e| ClientError(format!("get balance error: {:?}", e)))
+    }
 }
\ No newline at end of file
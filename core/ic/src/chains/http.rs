@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use candid::Principal;
+use ic_cdk::api::call::call_with_payment;
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformContext, TransformFunc,
+};
+use ic_web3::{helpers, rpc, RequestId, Transport};
+use ic_web3::error::{Error, TransportError};
+
+use crate::secret::RpcEndpoint;
+
+// used when no fixed cycles per call is set
+const HTTP_BASE_CYCLES: u64 = 400_000_000;
+const HTTP_CYCLES_PER_BYTE: u64 = 100_000;
+const DEFAULT_MAX_RESP_BYTES: u64 = 2_000_000;
+
+/// json rpc over canister http outcalls, like ic_web3's ICHttp,
+/// but sends the extra headers of the endpoint, e.g. provider api keys
+#[derive(Clone, Debug)]
+pub struct HttpTransport {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    endpoint: RpcEndpoint,
+    max_resp_bytes: Option<u64>,
+    cycles_per_call: Option<u64>,
+    id: AtomicUsize,
+}
+
+impl HttpTransport {
+    pub fn new(endpoint: RpcEndpoint, max_resp_bytes: Option<u64>, cycles_per_call: Option<u64>) -> Self {
+        HttpTransport {
+            inner: Arc::new(Inner {
+                endpoint,
+                max_resp_bytes,
+                cycles_per_call,
+                id: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+fn request_headers(endpoint: &RpcEndpoint) -> Vec<HttpHeader> {
+    let mut headers = vec![HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    }];
+    headers.extend(endpoint.headers.iter().map(|(name, value)| HttpHeader {
+        name: name.clone(),
+        value: value.clone(),
+    }));
+    headers
+}
+
+async fn execute_rpc(inner: Arc<Inner>, request: rpc::Request) -> Result<rpc::Output, Error> {
+    let body = serde_json::to_vec(&request)
+        .map_err(|e| Error::Decoder(format!("encode request failed: {:?}", e)))?;
+    let headers = request_headers(&inner.endpoint);
+    let cycles = inner.cycles_per_call.unwrap_or(
+        HTTP_BASE_CYCLES + HTTP_CYCLES_PER_BYTE * (body.len() as u64 + inner.max_resp_bytes.unwrap_or(DEFAULT_MAX_RESP_BYTES))
+    );
+    let arg = CanisterHttpRequestArgument {
+        url: inner.endpoint.url.clone(),
+        max_response_bytes: inner.max_resp_bytes,
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body),
+        // the canister must export the `transform` query
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::id(),
+                method: "transform".to_string(),
+            }),
+            context: vec![],
+        }),
+    };
+    // do not put the url into errors, it may contain the api key, the reject message may echo it
+    let (resp, ): (HttpResponse, ) = call_with_payment(Principal::management_canister(), "http_request", (arg, ), cycles)
+        .await
+        .map_err(|(code, msg)| Error::Transport(TransportError::Message(format!("http outcall failed: {:?}, {}", code, inner.endpoint.redact(&msg)))))?;
+    serde_json::from_slice(&resp.body)
+        .map_err(|e| Error::InvalidResponse(format!("decode response failed: {:?}", e)))
+}
+
+impl Transport for HttpTransport {
+    type Out = Pin<Box<dyn Future<Output = ic_web3::error::Result<rpc::Value>> + Send>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        let id = self.inner.id.fetch_add(1, Ordering::AcqRel);
+        (id, helpers::build_request(id, method, params))
+    }
+
+    fn send(&self, _id: RequestId, call: rpc::Call) -> Self::Out {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let output = execute_rpc(inner, rpc::Request::Single(call)).await?;
+            helpers::to_result_from_output(output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::{CredentialPlacement, RpcCredential, RpcSecrets};
+
+    #[test]
+    fn it_sends_header_credentials() {
+        let mut secrets = RpcSecrets::new();
+        secrets.set_credential("https://rpc.example".into(), RpcCredential {
+            placement: CredentialPlacement::Header("x-api-key".into()),
+            secret: "s3cret".into(),
+        });
+        let endpoint = secrets.resolve("https://rpc.example");
+        let headers = request_headers(&endpoint);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, "Content-Type");
+        assert_eq!(headers[1].name, "x-api-key");
+        assert_eq!(headers[1].value, "s3cret");
+        assert!(!endpoint.url.contains("s3cret"));
+    }
+
+    #[test]
+    fn it_keeps_query_credentials_out_of_headers() {
+        let mut secrets = RpcSecrets::new();
+        secrets.set_credential("https://rpc.example".into(), RpcCredential {
+            placement: CredentialPlacement::Query("key".into()),
+            secret: "s3cret".into(),
+        });
+        let endpoint = secrets.resolve("https://rpc.example");
+        let headers = request_headers(&endpoint);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.iter().all(|h| !h.value.contains("s3cret")));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod evm;
+pub mod http;
 
-pub use evm::*;
\ No newline at end of file
+pub use evm::*;
+pub use http::*;
\ No newline at end of file
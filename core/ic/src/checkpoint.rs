@@ -0,0 +1,152 @@
+use std::convert::TryInto;
+use candid::{CandidType, Deserialize};
+use ic_web3::types::H256;
+use accumulator::{ConsistencyProof, Tree, TREE_DEPTH};
+
+// oldest pending roots are dropped beyond this, a proof to a newer root supersedes them
+pub const MAX_PENDING_ROOTS: usize = 100;
+
+/// last root of the origin chain proven to extend the previous ones,
+/// when required, fetched roots wait in pending until a consistency proof from it is submitted
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RootCheckpoint {
+    pub required: bool,
+    pub count: u32, // leaf count of root
+    pub root: Vec<u8>, // empty for the empty tree
+    pub pending: Vec<Vec<u8>>, // fetched roots waiting for a proof, oldest first
+}
+
+impl RootCheckpoint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_required(&mut self, required: bool) {
+        self.required = required;
+    }
+
+    /// anchor the checkpoint to a trusted root, e.g. when enabling the check on a running chain
+    pub fn set_checkpoint(&mut self, count: u32, root: H256) {
+        self.count = count;
+        self.root = root.as_bytes().to_vec();
+        self.pending.clear();
+    }
+
+    pub fn root(&self) -> H256 {
+        if self.root.is_empty() {
+            Tree::<TREE_DEPTH>::initial_root()
+        } else {
+            H256::from_slice(&self.root)
+        }
+    }
+
+    /// add a fetched root, returns false if it is already pending
+    pub fn add_pending(&mut self, root: H256) -> bool {
+        let root = root.as_bytes().to_vec();
+        if self.pending.contains(&root) {
+            return false;
+        }
+        if self.pending.len() >= MAX_PENDING_ROOTS {
+            self.pending.remove(0);
+        }
+        self.pending.push(root);
+        true
+    }
+
+    /// check the proof from the checkpoint to a pending root, on success the root becomes the checkpoint
+    /// and the roots pending before it are dropped
+    pub fn accept(&mut self, proof: &ConsistencyProof<TREE_DEPTH>, new_root: H256) -> Result<(), String> {
+        if proof.old_count != self.count as usize {
+            return Err(format!("proof from count {}, checkpoint count is {}", proof.old_count, self.count));
+        }
+        let pos = self.pending.iter()
+            .position(|r| r.as_slice() == new_root.as_bytes())
+            .ok_or("root not pending".to_string())?;
+        let new_count: u32 = proof.new_count.try_into().map_err(|_| "new count too large".to_string())?;
+        proof.verify(self.root(), new_root).map_err(|e| format!("inconsistent root: {:?}", e))?;
+
+        self.count = new_count;
+        self.root = new_root.as_bytes().to_vec();
+        self.pending.drain(..=pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accumulator::Merkle;
+
+    fn tree(count: u64) -> Tree<TREE_DEPTH> {
+        (1..=count).map(H256::from_low_u64_be).collect()
+    }
+
+    fn checkpoint(pending: &[H256]) -> RootCheckpoint {
+        let mut checkpoint = RootCheckpoint::new();
+        checkpoint.set_required(true);
+        checkpoint.set_checkpoint(3, tree(3).root());
+        for root in pending {
+            assert!(checkpoint.add_pending(*root));
+        }
+        checkpoint
+    }
+
+    #[test]
+    fn it_accepts_a_consistent_root() {
+        let (mid, new) = (tree(5), tree(9));
+        let mut checkpoint = checkpoint(&[mid.root(), new.root()]);
+        assert!(!checkpoint.add_pending(new.root()));
+
+        checkpoint.accept(&new.prove_consistency(3).unwrap(), new.root()).unwrap();
+        assert_eq!(checkpoint.count, 9);
+        assert_eq!(checkpoint.root(), new.root());
+        // roots pending before it are dropped
+        assert!(checkpoint.pending.is_empty());
+
+        // the next proof starts from the new count
+        let newer = tree(12);
+        checkpoint.add_pending(newer.root());
+        assert!(checkpoint.accept(&newer.prove_consistency(3).unwrap(), newer.root()).is_err());
+        checkpoint.accept(&newer.prove_consistency(9).unwrap(), newer.root()).unwrap();
+        assert_eq!(checkpoint.count, 12);
+    }
+
+    #[test]
+    fn it_rejects_a_wrong_new_count() {
+        let new = tree(9);
+        let mut checkpoint = checkpoint(&[new.root()]);
+        let proof = new.prove_consistency(3).unwrap();
+        for new_count in [3, 8, 10, 1000] {
+            let mut wrong = proof;
+            wrong.new_count = new_count;
+            assert!(checkpoint.accept(&wrong, new.root()).is_err(), "new_count {}", new_count);
+            assert_eq!(checkpoint.count, 3);
+            assert_eq!(checkpoint.pending.len(), 1);
+        }
+        checkpoint.accept(&proof, new.root()).unwrap();
+        assert_eq!(checkpoint.count, 9);
+    }
+
+    #[test]
+    fn it_keeps_the_newest_pending_roots() {
+        let mut checkpoint = checkpoint(&[]);
+        for count in 4..4 + MAX_PENDING_ROOTS as u64 + 5 {
+            assert!(checkpoint.add_pending(tree(count).root()));
+        }
+        assert_eq!(checkpoint.pending.len(), MAX_PENDING_ROOTS);
+        assert!(!checkpoint.pending.contains(&tree(8).root().as_bytes().to_vec()));
+
+        // the newest root still takes a proof from the checkpoint
+        let newest = tree(3 + MAX_PENDING_ROOTS as u64 + 5);
+        checkpoint.accept(&newest.prove_consistency(3).unwrap(), newest.root()).unwrap();
+        assert!(checkpoint.pending.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_roots_not_pending() {
+        let new = tree(9);
+        let mut checkpoint = checkpoint(&[]);
+        let e = checkpoint.accept(&new.prove_consistency(3).unwrap(), new.root()).unwrap_err();
+        assert_eq!(e, "root not pending");
+    }
+}
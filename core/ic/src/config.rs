@@ -1,4 +1,5 @@
 use candid::{Deserialize, CandidType, Principal};
+use crate::secret::redact_urls;
 
 #[derive(CandidType, Deserialize, Clone)]
 pub enum ChainType {
@@ -56,4 +57,23 @@ impl ChainConfig {
     pub fn add_urls(&mut self, urls: Vec<String>) {
         self.rpc_urls.extend(urls);
     }
+
+    // return false if the url is not found
+    pub fn remove_rpc_url(&mut self, url: &str) -> bool {
+        let len = self.rpc_urls.len();
+        self.rpc_urls.retain(|u| u != url);
+        self.rpc_urls.len() != len
+    }
+
+    pub fn set_rpc_urls(&mut self, urls: Vec<String>) {
+        self.rpc_urls = urls;
+    }
+
+    /// config for public queries, rpc urls are redacted
+    pub fn redacted(&self) -> ChainConfig {
+        ChainConfig {
+            rpc_urls: redact_urls(&self.rpc_urls),
+            ..self.clone()
+        }
+    }
 }
\ No newline at end of file
@@ -1,11 +1,5 @@
-
-
-
-// mainnet
-pub const KEY_NAME: &str = "test_key_1";
-
-// local replica
-// pub const KEY_NAME: &str = "dfx_test_key";
+// ecdsa key the proxy signed with before the key name was an init arg
+pub const LEGACY_KEY_NAME: &str = "test_key_1";
 
 pub const MAX_RESP_BYTES: Option<u64> = Some(500);
 pub const CYCLES_PER_CALL: Option<u64> = None;
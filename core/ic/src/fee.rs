@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use candid::{CandidType, Deserialize, Principal};
+
+/// cycles charged by the proxy for delivering one message, base + per byte of the raw message
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+pub struct FeeConfig {
+    pub ic_base: u64,      // inter-canister call to the recipient
+    pub ic_per_byte: u64,
+    pub evm_base: u64,     // threshold ecdsa signing + http outcalls
+    pub evm_per_byte: u64,
+}
+
+impl FeeConfig {
+    pub fn fee(&self, destination: u32, len: usize) -> u64 {
+        if destination == 0 {
+            self.ic_base + self.ic_per_byte * len as u64
+        } else {
+            self.evm_base + self.evm_per_byte * len as u64
+        }
+    }
+}
+
+/// who paid the fee of a message
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeePayer {
+    Attached,      // cycles attached to process_message by the relayer
+    App(Principal), // prepaid balance of the recipient canister
+}
+
+/// a valid message whose fee was not paid, its leaf index has moved on,
+/// it waits here until the fee is paid with `pay_message`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UnpaidMessage {
+    pub message: Vec<u8>,
+    pub relayer: Principal,
+    pub fee: u64,
+}
+
+/// prepaid cycles balances of app canisters
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CyclesLedger {
+    pub fee_config: FeeConfig,
+    pub balances: HashMap<Principal, u64>,
+    pub collected: u64, // total fees charged
+    pub unpaid: HashMap<String, UnpaidMessage>, // message hash hex => message
+}
+
+impl CyclesLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_fee_config(&mut self, config: FeeConfig) {
+        self.fee_config = config;
+    }
+
+    pub fn fee(&self, destination: u32, len: usize) -> u64 {
+        self.fee_config.fee(destination, len)
+    }
+
+    pub fn balance(&self, app: &Principal) -> u64 {
+        self.balances.get(app).cloned().unwrap_or_default()
+    }
+
+    pub fn deposit(&mut self, app: Principal, amount: u64) -> u64 {
+        let balance = self.balances.entry(app).or_default();
+        *balance += amount;
+        *balance
+    }
+
+    pub fn withdraw(&mut self, app: Principal, amount: u64) -> Result<u64, String> {
+        let balance = self.balances.entry(app).or_default();
+        if *balance < amount {
+            return Err(format!("Insufficient balance: {} < {}", balance, amount));
+        }
+        *balance -= amount;
+        Ok(*balance)
+    }
+
+    /// charge `fee` from the prepaid balance of `app`
+    pub fn charge(&mut self, app: Principal, fee: u64) -> Result<(), String> {
+        self.withdraw(app, fee)
+            .map_err(|_| format!("Insufficient cycles: require {} cycles, app {} has {}", fee, app, self.balance(&app)))?;
+        self.collected += fee;
+        Ok(())
+    }
+
+    pub fn add_collected(&mut self, fee: u64) {
+        self.collected += fee;
+    }
+
+    pub fn add_unpaid(&mut self, msg_hash: String, message: UnpaidMessage) {
+        self.unpaid.insert(msg_hash, message);
+    }
+
+    pub fn take_unpaid(&mut self, msg_hash: &str) -> Option<UnpaidMessage> {
+        self.unpaid.remove(msg_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_unpaid_messages_until_taken() {
+        let mut ledger = CyclesLedger::new();
+        ledger.set_fee_config(FeeConfig { ic_base: 100, ic_per_byte: 1, evm_base: 1000, evm_per_byte: 10 });
+        let app = Principal::anonymous();
+        let fee = ledger.fee(0, 10);
+        assert_eq!(fee, 110);
+        assert!(ledger.charge(app, fee).is_err());
+
+        ledger.add_unpaid("aa".into(), UnpaidMessage { message: vec![1], relayer: app, fee });
+        assert!(ledger.take_unpaid("bb").is_none());
+
+        // the app prepays, the message can be paid for now
+        ledger.deposit(app, 200);
+        let unpaid = ledger.take_unpaid("aa").unwrap();
+        ledger.charge(app, unpaid.fee).unwrap();
+        assert_eq!(ledger.balance(&app), 90);
+        assert_eq!(ledger.collected, 110);
+        assert!(ledger.take_unpaid("aa").is_none());
+    }
+}
@@ -0,0 +1,326 @@
+use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use candid::{CandidType, Deserialize, Int, Principal};
+
+use crate::outbound::QueuedMessage;
+
+// price is the value of 1 native token (1e18 wei) in the report unit, e.g. usd with 6 decimals
+const WEI_PER_TOKEN: u128 = 1_000_000_000_000_000_000;
+// give up a receipt after this many sync rounds
+pub const MAX_RECEIPT_ATTEMPTS: u32 = 20;
+// oldest alerts are dropped beyond this
+pub const MAX_ALERTS: usize = 100;
+
+/// mirror of OmnicFeeManager's native fee on an origin chain, in wei
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+pub struct FeeSchedule {
+    pub base: u128,
+    pub per_byte: u128,
+}
+
+impl FeeSchedule {
+    pub fn fee(&self, payload_len: usize) -> u128 {
+        self.base.saturating_add(self.per_byte.saturating_mul(payload_len as u128))
+    }
+}
+
+/// a message sent to an evm destination, waiting for its receipt
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingDelivery {
+    pub txhash: String,
+    pub origin: u32,
+    pub destination: u32,
+    pub nonce: u32,
+    pub fee_paid: u128, // origin chain wei
+    pub attempts: u32,
+    pub message_hash: String,
+    pub batch_size: u32, // messages sharing the tx, 1 if it was sent alone
+    pub relayer: Option<Principal>, // rewarded once the receipt shows the message succeeded
+}
+
+/// gas and per message results of a mined tx, from the ProcessMessage and ProcessBatchEntry logs
+#[derive(Clone)]
+pub struct DeliveryReceipt {
+    pub gas_used: u128,
+    pub gas_price: u128,
+    pub results: HashMap<String, bool>, // message hash hex => success
+    pub entries: HashMap<String, Vec<(u64, bool)>>, // batch message hash hex => (entry index, success)
+}
+
+impl DeliveryReceipt {
+    /// whether the message succeeded in the tx, false if the tx reverted and left no log
+    pub fn success(&self, message_hash: &str) -> bool {
+        self.results.get(message_hash).cloned().unwrap_or_default()
+    }
+
+    /// results of the entries of a batch message by index, empty if the tx reverted
+    pub fn entry_results(&self, message_hash: &str) -> Vec<(u64, bool)> {
+        let mut entries = self.entries.get(message_hash).cloned().unwrap_or_default();
+        entries.sort();
+        entries
+    }
+}
+
+/// profit and loss of deliveries to one destination chain
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ChainPnL {
+    pub messages: u64,
+    pub gas_used: u128,
+    pub gas_spent: u128, // destination chain wei
+    pub income: u128,    // source fees, in the report unit
+    pub cost: u128,      // destination gas, in the report unit
+}
+
+impl ChainPnL {
+    /// income minus cost, saturated at the bounds of i128
+    pub fn profit(&self) -> i128 {
+        let income = i128::try_from(self.income).unwrap_or(i128::MAX);
+        let cost = i128::try_from(self.cost).unwrap_or(i128::MAX);
+        income.saturating_sub(cost)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PnLReport {
+    pub chain_id: u32,
+    pub pnl: ChainPnL,
+    pub profit: Int,
+    pub pending: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BalanceAlert {
+    pub chain_id: u32,
+    pub balance: u128,
+    pub threshold: u128,
+    pub timestamp: u64,
+}
+
+/// reconciles the fees paid at the source against the gas the proxy spends at the destination
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct GasLedger {
+    pub prices: HashMap<u32, u128>,
+    pub schedules: HashMap<u32, FeeSchedule>,
+    pub pending: VecDeque<PendingDelivery>,
+    pub reports: HashMap<u32, ChainPnL>,
+    pub alerts: VecDeque<BalanceAlert>,
+    pub low: HashSet<u32>, // chains below the low water mark since the last alert
+}
+
+pub fn to_value(wei: u128, price: u128) -> u128 {
+    wei.saturating_mul(price) / WEI_PER_TOKEN
+}
+
+impl GasLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_price(&mut self, chain_id: u32, price: u128) {
+        self.prices.insert(chain_id, price);
+    }
+
+    pub fn set_fee_schedule(&mut self, chain_id: u32, schedule: FeeSchedule) {
+        self.schedules.insert(chain_id, schedule);
+    }
+
+    fn price(&self, chain_id: u32) -> u128 {
+        self.prices.get(&chain_id).cloned().unwrap_or_default()
+    }
+
+    /// fee the sender paid on `origin` for a payload of `payload_len` bytes
+    pub fn source_fee(&self, origin: u32, payload_len: usize) -> u128 {
+        self.schedules.get(&origin).map(|s| s.fee(payload_len)).unwrap_or_default()
+    }
+
+    /// add a message sent alone or in a processMessageBatch tx of `batch_size` messages,
+    /// its outcome is recorded when the receipt is synced
+    pub fn add_pending(&mut self, txhash: String, destination: u32, message: &QueuedMessage, batch_size: u32) {
+        let fee_paid = self.source_fee(message.origin, message.payload_len as usize);
+        self.pending.push_back(PendingDelivery {
+            txhash,
+            origin: message.origin,
+            destination,
+            nonce: message.nonce,
+            fee_paid,
+            attempts: 0,
+            message_hash: message.message_hash.clone(),
+            batch_size,
+            relayer: message.relayer,
+        });
+    }
+
+    pub fn take_pending(&mut self) -> Vec<PendingDelivery> {
+        self.pending.drain(..).collect()
+    }
+
+    /// put back a delivery whose receipt is not available yet, returns false if it is given up
+    pub fn retry_pending(&mut self, mut delivery: PendingDelivery) -> bool {
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_RECEIPT_ATTEMPTS {
+            return false;
+        }
+        self.pending.push_back(delivery);
+        true
+    }
+
+    /// settle a delivery with the gas from its receipt, convert both sides with the current prices,
+    /// messages sent in one batch share its gas evenly
+    pub fn settle(&mut self, delivery: &PendingDelivery, gas_used: u128, gas_price: u128) {
+        let gas_used = gas_used / delivery.batch_size.max(1) as u128;
+        let gas_spent = gas_used.saturating_mul(gas_price);
+        let income = to_value(delivery.fee_paid, self.price(delivery.origin));
+        let cost = to_value(gas_spent, self.price(delivery.destination));
+        let pnl = self.reports.entry(delivery.destination).or_default();
+        pnl.messages += 1;
+        pnl.gas_used = pnl.gas_used.saturating_add(gas_used);
+        pnl.gas_spent = pnl.gas_spent.saturating_add(gas_spent);
+        pnl.income = pnl.income.saturating_add(income);
+        pnl.cost = pnl.cost.saturating_add(cost);
+    }
+
+    pub fn reports(&self) -> Vec<PnLReport> {
+        self.reports.iter().map(|(chain_id, pnl)| {
+            let pending = self.pending.iter().filter(|d| d.destination == *chain_id).count() as u64;
+            PnLReport {
+                chain_id: *chain_id,
+                pnl: pnl.clone(),
+                profit: Int::from(pnl.profit()),
+                pending,
+            }
+        }).collect()
+    }
+
+    /// record an alert when the balance drops below the low water mark of the chain,
+    /// not again until it has been restored
+    pub fn check_balance(&mut self, chain_id: u32, balance: u128, threshold: u128, timestamp: u64) -> Option<BalanceAlert> {
+        if balance >= threshold {
+            self.low.remove(&chain_id);
+            return None;
+        }
+        if !self.low.insert(chain_id) {
+            return None;
+        }
+        let alert = BalanceAlert { chain_id, balance, threshold, timestamp };
+        if self.alerts.len() >= MAX_ALERTS {
+            self.alerts.pop_front();
+        }
+        self.alerts.push_back(alert.clone());
+        Some(alert)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(hash: &str, nonce: u32, payload_len: u64, relayer: Option<Principal>) -> QueuedMessage {
+        QueuedMessage {
+            message: vec![],
+            origin: 5,
+            nonce,
+            message_hash: hash.into(),
+            payload_len,
+            queued_at: 0,
+            relayer,
+        }
+    }
+
+    #[test]
+    fn it_reconciles_source_fees_against_gas() {
+        let mut ledger = GasLedger::new();
+        ledger.set_fee_schedule(5, FeeSchedule { base: 1_000, per_byte: 10 });
+        // 1 token = 2000 units on the origin, 1000 units on the destination
+        ledger.set_price(5, 2_000 * WEI_PER_TOKEN);
+        ledger.set_price(80001, 1_000 * WEI_PER_TOKEN);
+
+        ledger.add_pending("0x1".into(), 80001, &sent("aa", 0, 100, None), 1);
+        ledger.add_pending("0x2".into(), 80001, &sent("bb", 1, 0, None), 2);
+        let pending = ledger.take_pending();
+        assert_eq!(pending[0].fee_paid, 2_000);
+        assert_eq!(pending[1].fee_paid, 1_000);
+
+        ledger.settle(&pending[0], 100, 10);
+        // batched messages share the gas of the tx
+        ledger.settle(&pending[1], 100, 10);
+        let report = &ledger.reports()[0];
+        assert_eq!(report.chain_id, 80001);
+        assert_eq!(report.pnl.messages, 2);
+        assert_eq!(report.pnl.gas_used, 150);
+        assert_eq!(report.pnl.gas_spent, 1_500);
+        assert_eq!(report.pnl.income, 6_000_000);
+        assert_eq!(report.pnl.cost, 1_500_000);
+        assert_eq!(report.profit, Int::from(4_500_000i128));
+        assert_eq!(report.pending, 0);
+    }
+
+    #[test]
+    fn it_saturates_the_profit() {
+        let pnl = ChainPnL { income: u128::MAX, ..Default::default() };
+        assert_eq!(pnl.profit(), i128::MAX);
+        let pnl = ChainPnL { cost: u128::MAX, ..Default::default() };
+        assert_eq!(pnl.profit(), -i128::MAX);
+        let pnl = ChainPnL { income: 5, cost: 8, ..Default::default() };
+        assert_eq!(pnl.profit(), -3);
+    }
+
+    #[test]
+    fn it_alerts_once_per_crossing() {
+        let mut ledger = GasLedger::new();
+        assert!(ledger.check_balance(1, 100, 50, 0).is_none());
+        assert!(ledger.check_balance(1, 40, 50, 1).is_some());
+        // still low, no new alert
+        assert!(ledger.check_balance(1, 30, 50, 2).is_none());
+        assert!(ledger.check_balance(5, 10, 50, 2).is_some());
+        assert!(ledger.check_balance(1, 60, 50, 3).is_none());
+        assert!(ledger.check_balance(1, 20, 50, 4).is_some());
+        assert_eq!(ledger.alerts.iter().map(|a| (a.chain_id, a.timestamp)).collect::<Vec<_>>(), vec![(1, 1), (5, 2), (1, 4)]);
+
+        for i in 0..MAX_ALERTS as u64 {
+            ledger.check_balance(1, 100, 50, i);
+            ledger.check_balance(1, 0, 50, i);
+        }
+        assert_eq!(ledger.alerts.len(), MAX_ALERTS);
+        assert_eq!(ledger.alerts[0].chain_id, 1);
+    }
+
+    #[test]
+    fn it_matches_receipts_to_sent_messages() {
+        let mut ledger = GasLedger::new();
+        let relayer = Principal::anonymous();
+        ledger.add_pending("0x1".into(), 80001, &sent("aa", 0, 0, Some(relayer)), 3);
+        ledger.add_pending("0x1".into(), 80001, &sent("bb", 1, 0, Some(relayer)), 3);
+        ledger.add_pending("0x1".into(), 80001, &sent("cc", 2, 0, Some(relayer)), 3);
+        // an ack has no relayer to reward
+        ledger.add_pending("0x2".into(), 80001, &sent("dd", 3, 0, None), 1);
+
+        let receipt = DeliveryReceipt {
+            gas_used: 300,
+            gas_price: 1,
+            results: vec![("aa".to_string(), true), ("bb".to_string(), false)].into_iter().collect(),
+            entries: vec![("aa".to_string(), vec![(1, false), (0, true)])].into_iter().collect(),
+        };
+        let pending = ledger.take_pending();
+        assert!(pending[..3].iter().all(|d| d.relayer == Some(relayer) && d.batch_size == 3));
+        assert!(pending[3].relayer.is_none() && pending[3].batch_size == 1);
+        // cc left no log, e.g. the tx reverted
+        let results: Vec<_> = pending[..3].iter().map(|d| receipt.success(&d.message_hash)).collect();
+        assert_eq!(results, vec![true, false, false]);
+        // entries of a batch message are recorded one by one
+        assert_eq!(receipt.entry_results("aa"), vec![(0, true), (1, false)]);
+        assert!(receipt.entry_results("cc").is_empty());
+    }
+
+    #[test]
+    fn it_gives_up_receipts() {
+        let mut ledger = GasLedger::new();
+        ledger.add_pending("0x1".into(), 1, &sent("aa", 0, 0, None), 1);
+        let mut delivery = ledger.take_pending().remove(0);
+        for _ in 1..MAX_RECEIPT_ATTEMPTS {
+            assert!(ledger.retry_pending(delivery));
+            delivery = ledger.take_pending().remove(0);
+        }
+        assert!(!ledger.retry_pending(delivery));
+        assert!(ledger.pending.is_empty());
+    }
+}
@@ -10,6 +10,18 @@ pub mod consts;
 pub mod state;
 pub mod call;
 pub mod error;
+pub mod secret;
+pub mod relayer;
+pub mod fee;
+pub mod gas;
+pub mod rotation;
+pub mod ratelimit;
+pub mod app;
+pub mod ack;
+pub mod address;
+pub mod schema;
+pub mod outbound;
+pub mod checkpoint;
 
 pub use types::*;
 pub use traits::*;
@@ -20,3 +32,14 @@ pub use consts::*;
 pub use state::*;
 pub use call::*;
 pub use error::*;
+pub use secret::*;
+pub use relayer::*;
+pub use fee::*;
+pub use gas::*;
+pub use rotation::*;
+pub use ratelimit::*;
+pub use app::*;
+pub use ack::*;
+pub use schema::*;
+pub use outbound::*;
+pub use checkpoint::*;
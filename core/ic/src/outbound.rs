@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use candid::{CandidType, Deserialize, Principal};
+
+/// batching of messages to evm chains, sent one tx per message if max_batch <= 1
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+pub struct OutboundConfig {
+    pub max_batch: u32, // flush when this many messages are queued for a chain
+    pub max_delay: u64, // seconds, flush when the oldest message waited this long
+}
+
+/// a message to an evm chain, waiting for a processMessageBatch tx or sent alone
+#[derive(CandidType, Deserialize, Clone)]
+pub struct QueuedMessage {
+    pub message: Vec<u8>,
+    pub origin: u32,
+    pub nonce: u32,
+    pub message_hash: String,
+    pub payload_len: u64,
+    pub queued_at: u64, // seconds
+    pub relayer: Option<Principal>, // rewarded once the receipt shows the message succeeded, None for acks
+}
+
+/// outbound messages by destination chain
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct OutboundQueue {
+    pub config: OutboundConfig,
+    pub queues: HashMap<u32, VecDeque<QueuedMessage>>,
+    pub flushing: HashSet<u32>, // chains with a batch being sent
+}
+
+impl OutboundQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_config(&mut self, config: OutboundConfig) {
+        self.config = config;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.max_batch > 1
+    }
+
+    /// queue a message, returns true if the queue of the chain is full
+    pub fn push(&mut self, chain_id: u32, message: QueuedMessage) -> bool {
+        let queue = self.queues.entry(chain_id).or_default();
+        queue.push_back(message);
+        queue.len() >= self.config.max_batch as usize
+    }
+
+    /// take the next batch of the chain, at most max_batch messages in queue order.
+    /// nothing while the previous batch is being sent, so batches go out in order and do not
+    /// race for the signer nonce, until `finish_batch`
+    pub fn take_batch(&mut self, chain_id: u32) -> Vec<QueuedMessage> {
+        if self.flushing.contains(&chain_id) {
+            return vec![];
+        }
+        let max = self.config.max_batch.max(1) as usize;
+        let batch: Vec<QueuedMessage> = match self.queues.get_mut(&chain_id) {
+            Some(queue) => {
+                let n = queue.len().min(max);
+                queue.drain(..n).collect()
+            }
+            None => vec![],
+        };
+        if !batch.is_empty() {
+            self.flushing.insert(chain_id);
+        }
+        batch
+    }
+
+    /// the batch taken for the chain was sent or put back
+    pub fn finish_batch(&mut self, chain_id: u32) {
+        self.flushing.remove(&chain_id);
+    }
+
+    /// put back a batch that was not sent, ahead of the messages queued since
+    pub fn requeue(&mut self, chain_id: u32, batch: Vec<QueuedMessage>) {
+        let queue = self.queues.entry(chain_id).or_default();
+        for message in batch.into_iter().rev() {
+            queue.push_front(message);
+        }
+    }
+
+    /// chains whose queue is full or waited max_delay, and not being flushed
+    pub fn due(&self, now: u64) -> Vec<u32> {
+        self.queues.iter()
+            .filter(|(chain_id, _)| !self.flushing.contains(chain_id))
+            .filter(|(_, q)| match q.front() {
+                Some(oldest) => {
+                    q.len() >= self.config.max_batch as usize
+                        || now >= oldest.queued_at.saturating_add(self.config.max_delay)
+                }
+                None => false,
+            })
+            .map(|(chain_id, _)| *chain_id)
+            .collect()
+    }
+
+    pub fn sizes(&self) -> Vec<(u32, u64)> {
+        self.queues.iter().map(|(chain_id, q)| (*chain_id, q.len() as u64)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(nonce: u32, queued_at: u64) -> QueuedMessage {
+        QueuedMessage {
+            message: vec![nonce as u8],
+            origin: 5,
+            nonce,
+            message_hash: hex::encode([nonce as u8; 32]),
+            payload_len: 0,
+            queued_at,
+            relayer: None,
+        }
+    }
+
+    fn nonces(batch: &[QueuedMessage]) -> Vec<u32> {
+        batch.iter().map(|q| q.nonce).collect()
+    }
+
+    #[test]
+    fn it_is_disabled_by_default() {
+        let mut queue = OutboundQueue::new();
+        assert!(!queue.enabled());
+        queue.set_config(OutboundConfig { max_batch: 1, max_delay: 0 });
+        assert!(!queue.enabled());
+    }
+
+    #[test]
+    fn it_takes_batches_in_order() {
+        let mut queue = OutboundQueue::new();
+        queue.set_config(OutboundConfig { max_batch: 3, max_delay: 60 });
+        assert!(queue.take_batch(1).is_empty());
+        for nonce in 0..5 {
+            queue.push(1, queued(nonce, 0));
+        }
+        assert_eq!(nonces(&queue.take_batch(1)), vec![0, 1, 2]);
+        queue.finish_batch(1);
+        assert_eq!(nonces(&queue.take_batch(1)), vec![3, 4]);
+        queue.finish_batch(1);
+        assert!(queue.take_batch(1).is_empty());
+    }
+
+    #[test]
+    fn it_flushes_full_or_late_queues() {
+        let mut queue = OutboundQueue::new();
+        queue.set_config(OutboundConfig { max_batch: 2, max_delay: 60 });
+        assert!(!queue.push(1, queued(0, 100)));
+        assert!(queue.due(159).is_empty());
+        assert_eq!(queue.due(160), vec![1]);
+        // full queue
+        assert!(queue.push(1, queued(1, 150)));
+        assert!(!queue.push(2, queued(0, 150)));
+        assert_eq!(queue.due(100), vec![1]);
+        let mut sizes = queue.sizes();
+        sizes.sort();
+        assert_eq!(sizes, vec![(1, 2), (2, 1)]);
+    }
+
+    #[test]
+    fn it_requeues_failed_batches_first() {
+        let mut queue = OutboundQueue::new();
+        queue.set_config(OutboundConfig { max_batch: 2, max_delay: 60 });
+        for nonce in 0..3 {
+            queue.push(1, queued(nonce, 0));
+        }
+        let batch = queue.take_batch(1);
+        queue.push(1, queued(3, 0));
+        queue.requeue(1, batch);
+        queue.finish_batch(1);
+        assert_eq!(nonces(&queue.take_batch(1)), vec![0, 1]);
+        queue.finish_batch(1);
+        assert_eq!(nonces(&queue.take_batch(1)), vec![2, 3]);
+    }
+
+    #[test]
+    fn it_sends_one_batch_per_chain_at_a_time() {
+        let mut queue = OutboundQueue::new();
+        queue.set_config(OutboundConfig { max_batch: 2, max_delay: 60 });
+        for nonce in 0..4 {
+            queue.push(1, queued(nonce, 0));
+        }
+        queue.push(2, queued(0, 0));
+        let first = queue.take_batch(1);
+        // a second flush while the first batch is awaited gets nothing
+        assert!(queue.take_batch(1).is_empty());
+        assert_eq!(queue.due(100), vec![2]);
+        assert_eq!(nonces(&queue.take_batch(2)), vec![0]);
+
+        // the first batch failed, it goes out again before the rest
+        queue.requeue(1, first);
+        queue.finish_batch(1);
+        assert_eq!(queue.due(100), vec![1]);
+        assert_eq!(nonces(&queue.take_batch(1)), vec![0, 1]);
+    }
+}
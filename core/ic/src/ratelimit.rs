@@ -0,0 +1,308 @@
+use std::collections::{HashMap, VecDeque};
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::fee::FeePayer;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// messages over the limits beyond this are dropped
+pub const MAX_DEFERRED: usize = 1_000;
+
+/// token bucket config, `capacity` messages in a burst, refilled by `refill_per_sec`
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub capacity: u64,
+    pub refill_per_sec: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenBucket {
+    pub tokens: u64,
+    pub last_refill: u64, // ns
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: u64) -> Self {
+        TokenBucket {
+            tokens: limit.capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        if limit.refill_per_sec == 0 || now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let refill = (elapsed as u128 * limit.refill_per_sec as u128 / NANOS_PER_SEC as u128).min(u64::MAX as u128) as u64;
+        if refill == 0 {
+            return;
+        }
+        self.tokens = self.tokens.saturating_add(refill).min(limit.capacity);
+        if self.tokens == limit.capacity {
+            // a full bucket does not keep time towards the next token
+            self.last_refill = now;
+        } else {
+            // only move forward by the time the refilled tokens account for, never past elapsed
+            self.last_refill += (refill as u128 * NANOS_PER_SEC as u128 / limit.refill_per_sec as u128) as u64;
+        }
+    }
+
+    pub fn available(&mut self, limit: &RateLimit, now: u64) -> u64 {
+        self.refill(limit, now);
+        self.tokens
+    }
+
+    pub fn take(&mut self) {
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+}
+
+// (chain id, bytes32 address)
+pub type RateKey = (u32, Vec<u8>);
+
+/// a verified message over the rate limit, delivered later in arrival order
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DeferredMessage {
+    pub message: Vec<u8>,
+    pub relayer: Principal,
+    pub payer: FeePayer,
+    pub sender_key: RateKey,    // (origin, sender)
+    pub recipient_key: RateKey, // (destination, recipient)
+    pub deferred_at: u64,
+}
+
+/// token buckets by (origin, sender) and by recipient
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RateLimiter {
+    pub default_sender: Option<RateLimit>,
+    pub default_recipient: Option<RateLimit>, // ic apps can override it in their AppConfig
+    pub sender_buckets: HashMap<RateKey, TokenBucket>,
+    pub recipient_buckets: HashMap<RateKey, TokenBucket>,
+    pub deferred: VecDeque<DeferredMessage>,
+    pub inflight: Vec<RateKey>, // keys of the deferred messages being delivered
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_defaults(&mut self, sender: Option<RateLimit>, recipient: Option<RateLimit>) {
+        self.default_sender = sender;
+        self.default_recipient = recipient;
+    }
+
+    /// a message must wait behind the deferred ones with the same sender or recipient
+    pub fn is_blocked(&self, sender_key: &RateKey, recipient_key: &RateKey) -> bool {
+        self.inflight.iter().any(|k| k == sender_key || k == recipient_key)
+            || self.deferred.iter().any(|d| d.sender_key == *sender_key || d.recipient_key == *recipient_key)
+    }
+
+    /// take a token from both buckets, or none if either is empty
+    pub fn try_acquire(&mut self, sender_key: &RateKey, recipient_key: &RateKey, recipient_limit: Option<RateLimit>, now: u64) -> bool {
+        let sender_limit = self.default_sender;
+        let recipient_limit = recipient_limit.or(self.default_recipient);
+        if let Some(limit) = &sender_limit {
+            let bucket = self.sender_buckets.entry(sender_key.clone()).or_insert_with(|| TokenBucket::new(limit, now));
+            if bucket.available(limit, now) == 0 {
+                return false;
+            }
+        }
+        if let Some(limit) = &recipient_limit {
+            let bucket = self.recipient_buckets.entry(recipient_key.clone()).or_insert_with(|| TokenBucket::new(limit, now));
+            if bucket.available(limit, now) == 0 {
+                return false;
+            }
+        }
+        if sender_limit.is_some() {
+            self.sender_buckets.get_mut(sender_key).map(|b| b.take());
+        }
+        if recipient_limit.is_some() {
+            self.recipient_buckets.get_mut(recipient_key).map(|b| b.take());
+        }
+        true
+    }
+
+    /// queue a message to deliver later, false if the queue is full
+    pub fn defer(&mut self, message: DeferredMessage) -> bool {
+        if self.deferred.len() >= MAX_DEFERRED {
+            return false;
+        }
+        self.deferred.push_back(message);
+        true
+    }
+
+    /// take the deferred messages that can be delivered now, in order, a message stays if an
+    /// earlier one with the same sender or recipient stays or is still inflight. their keys are
+    /// inflight until `finish_inflight`, so new messages keep waiting behind them
+    pub fn take_ready<F: Fn(&DeferredMessage) -> Option<RateLimit>>(&mut self, now: u64, recipient_limit: F) -> Vec<DeferredMessage> {
+        let mut ready = Vec::new();
+        let mut kept = VecDeque::new();
+        let mut blocked: Vec<RateKey> = self.inflight.clone();
+        while let Some(d) = self.deferred.pop_front() {
+            let is_blocked = blocked.iter().any(|k| *k == d.sender_key || *k == d.recipient_key);
+            if !is_blocked && self.try_acquire(&d.sender_key, &d.recipient_key, recipient_limit(&d), now) {
+                self.inflight.push(d.sender_key.clone());
+                self.inflight.push(d.recipient_key.clone());
+                ready.push(d);
+            } else {
+                blocked.push(d.sender_key.clone());
+                blocked.push(d.recipient_key.clone());
+                kept.push_back(d);
+            }
+        }
+        self.deferred = kept;
+        ready
+    }
+
+    /// release the keys of a message returned by `take_ready` once it is delivered
+    pub fn finish_inflight(&mut self, message: &DeferredMessage) {
+        for key in [&message.sender_key, &message.recipient_key].iter() {
+            if let Some(i) = self.inflight.iter().position(|k| k == *key) {
+                self.inflight.swap_remove(i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = NANOS_PER_SEC;
+
+    fn key(n: u8) -> RateKey {
+        (1, vec![n; 32])
+    }
+
+    fn deferred(sender: u8, recipient: u8, n: u8) -> DeferredMessage {
+        DeferredMessage {
+            message: vec![n],
+            relayer: Principal::anonymous(),
+            payer: FeePayer::Attached,
+            sender_key: key(sender),
+            recipient_key: (0, vec![recipient; 32]),
+            deferred_at: 0,
+        }
+    }
+
+    #[test]
+    fn it_refills_the_bucket() {
+        let limit = RateLimit { capacity: 3, refill_per_sec: 2 };
+        let mut bucket = TokenBucket::new(&limit, 0);
+        for _ in 0..3 {
+            bucket.take();
+        }
+        assert_eq!(bucket.available(&limit, SEC / 4), 0);
+        // the partial token keeps accruing
+        assert_eq!(bucket.available(&limit, SEC * 3 / 4), 1);
+        assert_eq!(bucket.last_refill, SEC / 2);
+        assert_eq!(bucket.available(&limit, SEC), 2);
+        assert_eq!(bucket.available(&limit, 100 * SEC), 3);
+        assert_eq!(bucket.last_refill, 100 * SEC);
+    }
+
+    #[test]
+    fn it_does_not_overflow_on_large_rates() {
+        let limit = RateLimit { capacity: u64::MAX, refill_per_sec: u64::MAX };
+        let mut bucket = TokenBucket::new(&limit, 0);
+        bucket.tokens = 0;
+        assert_eq!(bucket.available(&limit, 10 * SEC), u64::MAX);
+        assert_eq!(bucket.last_refill, 10 * SEC);
+
+        let limit = RateLimit { capacity: u64::MAX, refill_per_sec: 1_000 };
+        let mut bucket = TokenBucket::new(&limit, 0);
+        bucket.tokens = 0;
+        let now = u64::MAX / 2;
+        let tokens = bucket.available(&limit, now);
+        assert_eq!(tokens, (now as u128 * 1_000 / SEC as u128) as u64);
+        assert!(bucket.last_refill <= now);
+    }
+
+    #[test]
+    fn it_defers_over_the_limit() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_defaults(Some(RateLimit { capacity: 1, refill_per_sec: 1 }), None);
+        let recipient = (0, vec![9; 32]);
+        assert!(limiter.try_acquire(&key(1), &recipient, None, 0));
+        assert!(!limiter.try_acquire(&key(1), &recipient, None, 0));
+        // other senders have their own bucket
+        assert!(limiter.try_acquire(&key(2), &recipient, None, 0));
+        // the recipient limit of an app applies on top
+        let app_limit = Some(RateLimit { capacity: 1, refill_per_sec: 1 });
+        assert!(limiter.try_acquire(&key(3), &recipient, app_limit, 0));
+        assert!(!limiter.try_acquire(&key(4), &recipient, app_limit, 0));
+        // an empty recipient bucket does not take the sender's token
+        assert_eq!(limiter.sender_buckets[&key(4)].tokens, 1);
+    }
+
+    #[test]
+    fn it_takes_ready_messages_in_order() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_defaults(Some(RateLimit { capacity: 1, refill_per_sec: 1 }), None);
+        // sender 1 used its token, its messages wait
+        assert!(limiter.try_acquire(&key(1), &(0, vec![9; 32]), None, 0));
+        limiter.defer(deferred(1, 7, 0));
+        limiter.defer(deferred(1, 7, 1));
+        limiter.defer(deferred(2, 8, 2));
+        // same recipient as a kept message, waits behind it
+        limiter.defer(deferred(3, 7, 3));
+        assert!(limiter.is_blocked(&key(1), &(0, vec![0; 32])));
+        assert!(limiter.is_blocked(&key(5), &(0, vec![7; 32])));
+
+        let ready = limiter.take_ready(0, |_| None);
+        assert_eq!(ready.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(limiter.deferred.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![0, 1, 3]);
+        // new messages of an inflight sender wait too
+        assert!(limiter.is_blocked(&key(2), &(0, vec![0; 32])));
+        limiter.finish_inflight(&ready[0]);
+        assert!(!limiter.is_blocked(&key(2), &(0, vec![0; 32])));
+
+        // one token per second for sender 1, its messages drain one by one in order,
+        // message 3 shares the recipient of message 1 and stays behind it
+        let ready = limiter.take_ready(SEC, |_| None);
+        assert_eq!(ready.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![0]);
+        limiter.finish_inflight(&ready[0]);
+        let ready = limiter.take_ready(SEC, |_| None);
+        assert!(ready.is_empty());
+        let ready = limiter.take_ready(2 * SEC, |_| None);
+        assert_eq!(ready.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(limiter.deferred.is_empty());
+    }
+
+    #[test]
+    fn it_skips_keys_still_inflight() {
+        let mut limiter = RateLimiter::new();
+        limiter.defer(deferred(1, 7, 0));
+        limiter.defer(deferred(2, 8, 1));
+        let first = limiter.take_ready(0, |_| None);
+        assert_eq!(first.len(), 2);
+
+        // a drain overlapping the first one does not pass its messages
+        limiter.defer(deferred(1, 9, 2));
+        limiter.defer(deferred(3, 8, 3));
+        limiter.defer(deferred(4, 6, 4));
+        let second = limiter.take_ready(0, |_| None);
+        assert_eq!(second.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![4]);
+
+        // finishing the second drain keeps the keys of the first
+        limiter.finish_inflight(&second[0]);
+        assert!(limiter.is_blocked(&key(1), &(0, vec![0; 32])));
+        limiter.finish_inflight(&first[0]);
+        let ready = limiter.take_ready(0, |_| None);
+        assert_eq!(ready.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![2]);
+        limiter.finish_inflight(&first[1]);
+        let ready = limiter.take_ready(0, |_| None);
+        assert_eq!(ready.iter().map(|d| d.message[0]).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn it_bounds_the_deferred_messages() {
+        let mut limiter = RateLimiter::new();
+        for i in 0..MAX_DEFERRED {
+            assert!(limiter.defer(deferred(1, 7, i as u8)));
+        }
+        assert!(!limiter.defer(deferred(2, 8, 0)));
+        assert_eq!(limiter.deferred.len(), MAX_DEFERRED);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{HashMap, HashSet};
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RelayerStats {
+    pub submitted: u64, // process_message calls
+    pub valid: u64,     // messages passed verification
+    pub invalid: u64,   // messages failed to parse or verify
+    pub delivered: u64, // messages delivered to the destination
+}
+
+/// reward for each message relayed from an origin chain
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+pub struct RewardConfig {
+    pub cycles: u64,  // paid from the proxy's cycles balance
+    pub native: u128, // paid from the fees collected by the origin chain's omnic contract, in wei
+}
+
+/// unclaimed rewards of a relayer
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RelayerRewards {
+    pub cycles: u64,
+    pub native: HashMap<u32, u128>, // origin chain id => amount
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct RelayerDB {
+    // when enabled, only principals with the relayer role can call process_message
+    pub allowlist_enabled: bool,
+    pub banned: HashSet<Principal>,
+    pub stats: HashMap<Principal, RelayerStats>,
+    pub reward_configs: HashMap<u32, RewardConfig>, // origin chain id => reward
+    pub rewards: HashMap<Principal, RelayerRewards>,
+    pub native_fees: HashMap<u32, u128>, // origin chain id => fees collected but not credited yet
+}
+
+impl RelayerDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.allowlist_enabled = enabled;
+    }
+
+    pub fn ban(&mut self, relayer: Principal) {
+        self.banned.insert(relayer);
+    }
+
+    pub fn unban(&mut self, relayer: Principal) -> bool {
+        self.banned.remove(&relayer)
+    }
+
+    pub fn is_banned(&self, relayer: &Principal) -> bool {
+        self.banned.contains(relayer)
+    }
+
+    fn entry(&mut self, relayer: Principal) -> &mut RelayerStats {
+        self.stats.entry(relayer).or_default()
+    }
+
+    pub fn incr_submitted(&mut self, relayer: Principal) {
+        self.entry(relayer).submitted += 1;
+    }
+
+    pub fn incr_valid(&mut self, relayer: Principal) {
+        self.entry(relayer).valid += 1;
+    }
+
+    pub fn incr_invalid(&mut self, relayer: Principal) {
+        self.entry(relayer).invalid += 1;
+    }
+
+    pub fn incr_delivered(&mut self, relayer: Principal) {
+        self.entry(relayer).delivered += 1;
+    }
+
+    pub fn get_stats(&self, relayer: &Principal) -> RelayerStats {
+        self.stats.get(relayer).cloned().unwrap_or_default()
+    }
+
+    pub fn all_stats(&self) -> Vec<(Principal, RelayerStats)> {
+        self.stats.iter().map(|(p, s)| (*p, s.clone())).collect()
+    }
+
+    pub fn set_reward_config(&mut self, origin: u32, config: RewardConfig) {
+        self.reward_configs.insert(origin, config);
+    }
+
+    pub fn get_reward_config(&self, origin: u32) -> RewardConfig {
+        self.reward_configs.get(&origin).cloned().unwrap_or_default()
+    }
+
+    /// credit the reward of a delivered message from `origin` to the relayer who submitted it first,
+    /// `fee_paid` is the native fee of the message at the origin, native rewards never exceed the fees collected
+    pub fn credit_reward(&mut self, relayer: Principal, origin: u32, fee_paid: u128) {
+        let config = self.get_reward_config(origin);
+        let fees = self.native_fees.entry(origin).or_default();
+        *fees = fees.saturating_add(fee_paid);
+        let native = config.native.min(*fees);
+        *fees -= native;
+        if config.cycles == 0 && native == 0 {
+            return;
+        }
+        let rewards = self.rewards.entry(relayer).or_default();
+        rewards.cycles += config.cycles;
+        if native > 0 {
+            *rewards.native.entry(origin).or_default() += native;
+        }
+    }
+
+    pub fn get_rewards(&self, relayer: &Principal) -> RelayerRewards {
+        self.rewards.get(relayer).cloned().unwrap_or_default()
+    }
+
+    /// take all unclaimed cycles of the relayer, put them back with `refund_cycles` if the payout fails
+    pub fn take_cycles(&mut self, relayer: Principal) -> u64 {
+        match self.rewards.get_mut(&relayer) {
+            Some(r) => std::mem::take(&mut r.cycles),
+            None => 0,
+        }
+    }
+
+    pub fn refund_cycles(&mut self, relayer: Principal, amount: u64) {
+        self.rewards.entry(relayer).or_default().cycles += amount;
+    }
+
+    /// take all unclaimed native rewards of the relayer on `origin`
+    pub fn take_native(&mut self, relayer: Principal, origin: u32) -> u128 {
+        match self.rewards.get_mut(&relayer) {
+            Some(r) => r.native.remove(&origin).unwrap_or_default(),
+            None => 0,
+        }
+    }
+
+    pub fn refund_native(&mut self, relayer: Principal, origin: u32, amount: u128) {
+        *self.rewards.entry(relayer).or_default().native.entry(origin).or_default() += amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_caps_native_rewards_at_collected_fees() {
+        let relayer = Principal::anonymous();
+        let mut db = RelayerDB::new();
+        db.set_reward_config(5, RewardConfig { cycles: 10, native: 100 });
+
+        db.credit_reward(relayer, 5, 60);
+        assert_eq!(db.get_rewards(&relayer).native[&5], 60);
+        // the shortfall is not owed, later fees only cover later rewards
+        db.credit_reward(relayer, 5, 150);
+        assert_eq!(db.get_rewards(&relayer).native[&5], 160);
+        assert_eq!(db.native_fees[&5], 50);
+        db.credit_reward(relayer, 5, 0);
+        assert_eq!(db.get_rewards(&relayer).native[&5], 210);
+        assert_eq!(db.get_rewards(&relayer).cycles, 30);
+
+        // no fee schedule mirrored, no native reward
+        db.set_reward_config(1, RewardConfig { cycles: 0, native: 100 });
+        db.credit_reward(relayer, 1, 0);
+        assert!(db.get_rewards(&relayer).native.get(&1).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use candid::{CandidType, Deserialize};
+
+use crate::chain_state::ChainState;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum RotationStatus {
+    Derived,            // new address derived, waiting for the handover
+    Submitted(String),  // proxy sent setOmnicCanisterAddr from the old address, txhash
+    CalldataEmitted,    // waiting for the owner multisig to submit the calldata
+    Confirmed,          // contract points to the new address and the old address is drained
+}
+
+/// rotation of the signer on one chain
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChainRotation {
+    pub chain_id: u32,
+    pub old_addr: String,
+    pub new_addr: String,
+    pub new_derivation_path: Vec<Vec<u8>>,
+    pub calldata: Vec<u8>, // setOmnicCanisterAddr(new_addr)
+    pub status: RotationStatus,
+}
+
+impl ChainRotation {
+    /// point the chain at its new signer, the address is set after the path since changing the path clears it
+    pub fn switch(&self, chain: &mut ChainState) {
+        chain.set_derivation_path(self.new_derivation_path.clone());
+        chain.set_canister_addr(self.new_addr.clone());
+    }
+}
+
+/// an in-progress signer rotation, deliveries to the rotating chains are held until it
+/// is finished, then all chains switch to their new address at once
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Rotation {
+    pub epoch: u32, // bumped by each rotation, part of the new derivation paths
+    pub active: bool,
+    pub submit: bool, // proxy submits the handover, otherwise the owner multisig does
+    pub key_name: String, // new ecdsa key of a key migration, empty for a signer rotation
+    pub chains: HashMap<u32, ChainRotation>,
+}
+
+impl Rotation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&mut self, submit: bool) -> Result<u32, String> {
+        if self.active {
+            return Err("rotation in progress".into());
+        }
+        self.epoch += 1;
+        self.active = true;
+        self.submit = submit;
+        self.key_name.clear();
+        self.chains.clear();
+        Ok(self.epoch)
+    }
+
+    /// move the signers to `key_name` with their derivation paths kept, the proxy submits the handover
+    pub fn start_key_migration(&mut self, key_name: String) -> Result<u32, String> {
+        let epoch = self.start(true)?;
+        self.key_name = key_name;
+        Ok(epoch)
+    }
+
+    pub fn add_chain(&mut self, rotation: ChainRotation) {
+        self.chains.insert(rotation.chain_id, rotation);
+    }
+
+    pub fn set_status(&mut self, chain_id: u32, status: RotationStatus) {
+        if let Some(r) = self.chains.get_mut(&chain_id) {
+            r.status = status;
+        }
+    }
+
+    pub fn is_rotating(&self, chain_id: u32) -> bool {
+        self.active && self.chains.contains_key(&chain_id)
+    }
+
+    pub fn all_confirmed(&self) -> bool {
+        self.chains.values().all(|r| r.status == RotationStatus::Confirmed)
+    }
+
+    /// end the rotation, return the chains to switch
+    pub fn finish(&mut self) -> Vec<ChainRotation> {
+        self.active = false;
+        self.chains.drain().map(|(_, r)| r).collect()
+    }
+
+    /// end the rotation only if every chain is confirmed, so all of them switch together
+    pub fn finish_confirmed(&mut self) -> Option<Vec<ChainRotation>> {
+        if self.active && self.all_confirmed() {
+            Some(self.finish())
+        } else {
+            None
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = false;
+        self.key_name.clear();
+        self.chains.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_rotation(chain_id: u32, epoch: u32) -> ChainRotation {
+        ChainRotation {
+            chain_id,
+            old_addr: format!("0x{:040}", chain_id),
+            new_addr: format!("0x{:039}{}", chain_id, epoch),
+            new_derivation_path: vec![vec![1], chain_id.to_be_bytes().to_vec(), epoch.to_be_bytes().to_vec()],
+            calldata: vec![],
+            status: RotationStatus::Derived,
+        }
+    }
+
+    fn chain(chain_id: u32) -> ChainState {
+        let mut c = ChainState::default();
+        c.config.chain_id = chain_id;
+        c.set_canister_addr(format!("0x{:040}", chain_id));
+        c
+    }
+
+    #[test]
+    fn it_switches_all_chains_at_once() {
+        let mut rotation = Rotation::new();
+        let epoch = rotation.start(true).unwrap();
+        assert_eq!(epoch, 1);
+        assert!(rotation.start(true).is_err());
+        rotation.add_chain(chain_rotation(1, epoch));
+        rotation.add_chain(chain_rotation(5, epoch));
+        // deliveries to the rotating chains are held
+        assert!(rotation.is_rotating(1) && rotation.is_rotating(5));
+        assert!(!rotation.is_rotating(80001));
+
+        // handover approved and submitted on both, drained on one
+        rotation.set_status(1, RotationStatus::Submitted("0xaa".into()));
+        rotation.set_status(5, RotationStatus::Submitted("0xbb".into()));
+        rotation.set_status(1, RotationStatus::Confirmed);
+        assert!(rotation.finish_confirmed().is_none());
+        assert!(rotation.is_rotating(1));
+
+        rotation.set_status(5, RotationStatus::Confirmed);
+        let mut chains = vec![chain(1), chain(5)];
+        let done = rotation.finish_confirmed().unwrap();
+        assert_eq!(done.len(), 2);
+        for r in done.iter() {
+            let c = chains.iter_mut().find(|c| c.config.chain_id == r.chain_id).unwrap();
+            r.switch(c);
+            assert_eq!(c.canister_addr, r.new_addr);
+            assert_eq!(c.derivation_path, r.new_derivation_path);
+        }
+        assert!(!rotation.active && rotation.chains.is_empty());
+        assert!(!rotation.is_rotating(1));
+        assert!(rotation.finish_confirmed().is_none());
+    }
+
+    #[test]
+    fn it_migrates_keys_as_a_rotation() {
+        let mut rotation = Rotation::new();
+        let epoch = rotation.start_key_migration("key_2".into()).unwrap();
+        assert!(rotation.submit);
+        assert_eq!(rotation.key_name, "key_2");
+        assert!(rotation.start_key_migration("key_3".into()).is_err());
+        assert!(rotation.start(false).is_err());
+
+        // deliveries are held until the contract points to the new address
+        rotation.add_chain(chain_rotation(1, epoch));
+        rotation.set_status(1, RotationStatus::Submitted("0xaa".into()));
+        assert!(rotation.is_rotating(1));
+        assert!(rotation.finish_confirmed().is_none());
+        rotation.set_status(1, RotationStatus::Confirmed);
+        assert_eq!(rotation.finish_confirmed().map(|d| d.len()), Some(1));
+        assert_eq!(rotation.key_name, "key_2");
+
+        // a signer rotation keeps the key
+        rotation.start(true).unwrap();
+        assert!(rotation.key_name.is_empty());
+        rotation.cancel();
+        rotation.start_key_migration("key_2".into()).unwrap();
+        rotation.cancel();
+        assert!(rotation.key_name.is_empty());
+    }
+
+    #[test]
+    fn it_aborts_a_rotation() {
+        let mut rotation = Rotation::new();
+        let epoch = rotation.start(false).unwrap();
+        let mut r = chain_rotation(1, epoch);
+        r.status = RotationStatus::CalldataEmitted;
+        rotation.add_chain(r);
+        rotation.cancel();
+        assert!(!rotation.is_rotating(1));
+        assert!(rotation.finish_confirmed().is_none());
+
+        // the next rotation derives new paths from a new epoch
+        assert_eq!(rotation.start(true).unwrap(), epoch + 1);
+        assert!(rotation.chains.is_empty());
+        assert!(rotation.all_confirmed());
+        // an active rotation without chains has nothing to switch
+        assert_eq!(rotation.finish_confirmed().map(|d| d.len()), Some(0));
+    }
+}
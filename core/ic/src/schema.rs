@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use candid::{CandidType, Deserialize};
+use ic_web3::ethabi::{decode, encode, ParamType, Token};
+use ic_web3::ethabi::param_type::{Reader, Writer};
+
+use crate::utils::keccak256;
+use crate::OmnicError;
+use crate::OmnicError::DecodeError;
+
+// typed payloads are sent as abi.encode(bytes4 tag, bytes data), data is the abi encoding of the fields,
+// the tag is the first 4 bytes of keccak256("Name(type1,type2,..)"), as a function selector
+
+pub type TypeTag = [u8; 4];
+
+/// field of a payload schema, kind is a solidity type, e.g. uint256, address, bytes32[]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SchemaField {
+    pub name: String,
+    pub kind: String,
+}
+
+/// a payload type known at runtime, registered in the proxy to show decoded payloads
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PayloadSchema {
+    pub name: String,
+    pub fields: Vec<SchemaField>,
+}
+
+impl PayloadSchema {
+    pub fn param_types(&self) -> Result<Vec<ParamType>, OmnicError> {
+        self.fields.iter().map(|f| Ok(Reader::read(&f.kind)?)).collect()
+    }
+
+    /// canonical signature, e.g. Transfer(address,uint256)
+    pub fn signature(&self) -> Result<String, OmnicError> {
+        let types = self.param_types()?.iter().map(Writer::write).collect::<Vec<_>>();
+        Ok(format!("{}({})", self.name, types.join(",")))
+    }
+
+    pub fn type_tag(&self) -> Result<TypeTag, OmnicError> {
+        let hash = keccak256(self.signature()?.as_bytes());
+        Ok([hash[0], hash[1], hash[2], hash[3]])
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<Token>, OmnicError> {
+        Ok(decode(&self.param_types()?, data)?)
+    }
+
+    /// Name { field: value, .. }
+    pub fn format(&self, tokens: &[Token]) -> String {
+        let fields = self.fields.iter().zip(tokens)
+            .map(|(f, t)| format!("{}: {}", f.name, t))
+            .collect::<Vec<_>>();
+        format!("{} {{ {} }}", self.name, fields.join(", "))
+    }
+}
+
+/// split a typed payload into its tag and field data
+pub fn split_payload(body: &[u8]) -> Result<(TypeTag, Vec<u8>), OmnicError> {
+    let res = decode(&[ParamType::FixedBytes(4), ParamType::Bytes], body)?;
+    let tag = res[0].clone().into_fixed_bytes().ok_or(DecodeError("get type tag failed".into()))?;
+    let data = res[1].clone().into_bytes().ok_or(DecodeError("get payload data failed".into()))?;
+    Ok(([tag[0], tag[1], tag[2], tag[3]], data))
+}
+
+pub fn join_payload(tag: TypeTag, data: Vec<u8>) -> Vec<u8> {
+    encode(&[Token::FixedBytes(tag.to_vec()), Token::Bytes(data)])
+}
+
+/// a payload type defined once in rust, encoded as abi for the evm side and candid for the ic side
+pub trait Payload: Sized {
+    fn schema() -> PayloadSchema;
+    fn to_tokens(&self) -> Vec<Token>;
+    fn from_tokens(tokens: Vec<Token>) -> Result<Self, OmnicError>;
+
+    fn type_tag() -> TypeTag {
+        Self::schema().type_tag().expect("invalid payload schema")
+    }
+
+    /// message body, abi.encode(bytes4 tag, bytes data)
+    fn encode(&self) -> Vec<u8> {
+        join_payload(Self::type_tag(), encode(&self.to_tokens()))
+    }
+
+    fn decode(body: &[u8]) -> Result<Self, OmnicError> {
+        let (tag, data) = split_payload(body)?;
+        if tag != Self::type_tag() {
+            return Err(DecodeError(format!("type tag mismatch: {}", hex::encode(tag))));
+        }
+        Self::from_tokens(Self::schema().decode(&data)?)
+    }
+
+    fn to_candid(&self) -> Result<Vec<u8>, OmnicError>
+    where
+        Self: CandidType,
+    {
+        candid::encode_one(self).map_err(|e| DecodeError(format!("candid encode failed: {:?}", e)))
+    }
+
+    fn from_candid(data: &[u8]) -> Result<Self, OmnicError>
+    where
+        Self: CandidType + for<'de> Deserialize<'de>,
+    {
+        candid::decode_one(data).map_err(|e| DecodeError(format!("candid decode failed: {:?}", e)))
+    }
+}
+
+/// schemas by type tag
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct SchemaRegistry {
+    pub schemas: HashMap<Vec<u8>, PayloadSchema>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// register a schema, returns its tag
+    pub fn register(&mut self, schema: PayloadSchema) -> Result<TypeTag, String> {
+        let tag = schema.type_tag().map_err(|e| format!("invalid schema: {:?}", e))?;
+        self.schemas.insert(tag.to_vec(), schema);
+        Ok(tag)
+    }
+
+    pub fn unregister(&mut self, tag: &[u8]) -> Option<PayloadSchema> {
+        self.schemas.remove(tag)
+    }
+
+    /// decoded text of a message body, None if it is not a payload of a registered schema
+    pub fn format(&self, body: &[u8]) -> Option<String> {
+        let (tag, data) = split_payload(body).ok()?;
+        let schema = self.schemas.get(tag.as_slice())?;
+        let tokens = schema.decode(&data).ok()?;
+        Some(schema.format(&tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_web3::types::{Address, U256};
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct Transfer {
+        to: Vec<u8>,
+        amount: u64,
+    }
+
+    impl Payload for Transfer {
+        fn schema() -> PayloadSchema {
+            PayloadSchema {
+                // named like the erc20 function to check the tag against its well known selector
+                name: "transfer".into(),
+                fields: vec![
+                    SchemaField { name: "to".into(), kind: "address".into() },
+                    SchemaField { name: "amount".into(), kind: "uint256".into() },
+                ],
+            }
+        }
+
+        fn to_tokens(&self) -> Vec<Token> {
+            vec![Token::Address(Address::from_slice(&self.to)), Token::Uint(self.amount.into())]
+        }
+
+        fn from_tokens(tokens: Vec<Token>) -> Result<Self, OmnicError> {
+            let to = tokens[0].clone().into_address().ok_or(DecodeError("get to failed".into()))?;
+            let amount = tokens[1].clone().into_uint().ok_or(DecodeError("get amount failed".into()))?;
+            Ok(Transfer { to: to.as_bytes().to_vec(), amount: amount.as_u64() })
+        }
+    }
+
+    fn transfer() -> Transfer {
+        Transfer { to: vec![0x11; 20], amount: 1000 }
+    }
+
+    #[test]
+    fn it_tags_like_a_selector() {
+        assert_eq!(Transfer::schema().signature().unwrap(), "transfer(address,uint256)");
+        assert_eq!(Transfer::type_tag(), [0xa9, 0x05, 0x9c, 0xbb]);
+
+        let bad = PayloadSchema {
+            name: "Bad".into(),
+            fields: vec![SchemaField { name: "x".into(), kind: "uintx".into() }],
+        };
+        assert!(bad.type_tag().is_err());
+    }
+
+    #[test]
+    fn it_round_trips_abi_payloads() {
+        let body = transfer().encode();
+        let (tag, data) = split_payload(&body).unwrap();
+        assert_eq!(tag, Transfer::type_tag());
+        assert_eq!(data, encode(&[Token::Address(Address::repeat_byte(0x11)), Token::Uint(U256::from(1000))]));
+        assert_eq!(Transfer::decode(&body).unwrap(), transfer());
+    }
+
+    #[test]
+    fn it_round_trips_candid_payloads() {
+        let data = transfer().to_candid().unwrap();
+        assert_eq!(Transfer::from_candid(&data).unwrap(), transfer());
+    }
+
+    #[test]
+    fn it_rejects_a_mismatched_tag() {
+        let (_, data) = split_payload(&transfer().encode()).unwrap();
+        let body = join_payload([0xde, 0xad, 0xbe, 0xef], data);
+        assert!(matches!(Transfer::decode(&body), Err(DecodeError(e)) if e.contains("type tag mismatch")));
+        assert!(Transfer::decode(&[0u8; 10]).is_err());
+    }
+
+    #[test]
+    fn it_formats_registered_payloads() {
+        let mut registry = SchemaRegistry::new();
+        let body = transfer().encode();
+        assert!(registry.format(&body).is_none());
+        assert_eq!(registry.register(Transfer::schema()).unwrap(), Transfer::type_tag());
+        let text = registry.format(&body).unwrap();
+        assert!(text.starts_with("transfer { to: "));
+        assert!(text.contains("amount: 3e8"));
+        assert!(registry.format(b"not a payload").is_none());
+        assert!(registry.unregister(&Transfer::type_tag()).is_some());
+        assert!(registry.format(&body).is_none());
+    }
+}
@@ -136,34 +136,71 @@ impl From<StateMachine> for StateMachineStable {
     }
 }
 
+/// admin has all the permissions of the other roles
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Role {
+    Admin,    // manage roles, chains, credentials and settings
+    Operator, // pause chains, retry messages, change rpc urls
+    Relayer,  // submit messages through process_message
+    Auditor,  // read records
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct StateInfo {
-    pub owners: HashSet<Principal>,
+    pub roles: HashMap<Principal, HashSet<Role>>,
     pub fetch_root_period: u64,
     pub fetch_roots_period: u64,
     pub query_rpc_number: u64,
+    pub breaker_threshold: u32, // consecutive failures before a chain is paused, 0 to disable
+    pub key_name: String, // threshold ecdsa key the proxy signs with
 }
 
 impl StateInfo {
     pub fn default() -> StateInfo {
         StateInfo {
-            owners: HashSet::default(),
+            roles: HashMap::default(),
             fetch_root_period: 1_000_000_000 * 60,
             fetch_roots_period: 1_000_000_000 * 90,
             query_rpc_number: 1,
+            breaker_threshold: 5,
+            key_name: String::new(),
         }
     }
 
-    pub fn add_owner(&mut self, owner: Principal) {
-        self.owners.insert(owner);
+    pub fn grant_role(&mut self, user: Principal, role: Role) {
+        self.roles.entry(user).or_default().insert(role);
+    }
+
+    // return false if the user does not have the role
+    pub fn revoke_role(&mut self, user: Principal, role: Role) -> bool {
+        match self.roles.get_mut(&user) {
+            Some(roles) => {
+                let removed = roles.remove(&role);
+                if roles.is_empty() {
+                    self.roles.remove(&user);
+                }
+                removed
+            }
+            None => false,
+        }
     }
 
-    pub fn delete_owner(&mut self, owner: Principal) {
-        self.owners.remove(&owner);
+    pub fn has_role(&self, user: Principal, role: Role) -> bool {
+        match self.roles.get(&user) {
+            Some(roles) => roles.contains(&role) || roles.contains(&Role::Admin),
+            None => false,
+        }
     }
 
-    pub fn is_owner(&self, user: Principal) -> bool {
-        self.owners.contains(&user)
+    pub fn get_roles(&self, user: Principal) -> Vec<Role> {
+        match self.roles.get(&user) {
+            Some(roles) => roles.iter().cloned().collect(),
+            None => Vec::default(),
+        }
+    }
+
+    pub fn admin_count(&self) -> usize {
+        self.roles.values().filter(|r| r.contains(&Role::Admin)).count()
     }
 
     pub fn set_fetch_period(&mut self, v1: u64, v2: u64) {
@@ -174,6 +211,40 @@ impl StateInfo {
     pub fn set_rpc_number(&mut self, n: u64) {
         self.query_rpc_number = n
     }
+
+    pub fn set_breaker_threshold(&mut self, n: u32) {
+        self.breaker_threshold = n
+    }
+
+    pub fn set_key_name(&mut self, key_name: String) {
+        self.key_name = key_name
+    }
+}
+
+/// StateInfo as stored before roles, restored when upgrading from that layout
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StateInfoV0 {
+    pub owners: HashSet<Principal>,
+    pub fetch_root_period: u64,
+    pub fetch_roots_period: u64,
+    pub query_rpc_number: u64,
+}
+
+/// owners become admins, the signing key stays the one the old layout used
+impl From<StateInfoV0> for StateInfo {
+    fn from(s: StateInfoV0) -> Self {
+        let mut info = StateInfo {
+            fetch_root_period: s.fetch_root_period,
+            fetch_roots_period: s.fetch_roots_period,
+            query_rpc_number: s.query_rpc_number,
+            key_name: crate::consts::LEGACY_KEY_NAME.to_string(),
+            ..StateInfo::default()
+        };
+        for owner in s.owners {
+            info.grant_role(owner, Role::Admin);
+        }
+        info
+    }
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -286,4 +357,41 @@ impl RecordDB {
 
         res
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_migrates_owners_to_admins() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let old = StateInfoV0 {
+            owners: HashSet::from_iter(vec![owner]),
+            fetch_root_period: 1,
+            fetch_roots_period: 2,
+            query_rpc_number: 3,
+        };
+        let info = StateInfo::from(old);
+        assert!(info.has_role(owner, Role::Admin));
+        assert!(info.has_role(owner, Role::Relayer));
+        assert_eq!(info.admin_count(), 1);
+        assert_eq!((info.fetch_root_period, info.fetch_roots_period, info.query_rpc_number), (1, 2, 3));
+        assert!(!info.has_role(Principal::anonymous(), Role::Auditor));
+    }
+
+    #[test]
+    fn it_decodes_the_old_layout() {
+        let owner = Principal::from_slice(&[2; 10]);
+        let old = StateInfoV0 {
+            owners: HashSet::from_iter(vec![owner]),
+            fetch_root_period: 60,
+            fetch_roots_period: 90,
+            query_rpc_number: 1,
+        };
+        let bytes = candid::encode_one(&old).unwrap();
+        assert!(candid::decode_one::<StateInfo>(&bytes).is_err());
+        let info = StateInfo::from(candid::decode_one::<StateInfoV0>(&bytes).unwrap());
+        assert!(info.has_role(owner, Role::Admin));
+    }
+}
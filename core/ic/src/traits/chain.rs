@@ -2,14 +2,25 @@ use ic_web3::types::H256;
 use async_trait::async_trait;
 
 use crate::error::OmnicError;
+use crate::gas::DeliveryReceipt;
 
 // each chain client should impl this trait
 #[async_trait]
 pub trait HomeContract {
     async fn dispatch_message(&self, caller: String, dst_chain: u32, msg: Vec<u8>) -> Result<H256, OmnicError>;
+    async fn dispatch_batch(&self, caller: String, dst_chain: u32, msg: Vec<u8>) -> Result<H256, OmnicError>;
+    async fn dispatch_message_batch(&self, caller: String, dst_chain: u32, msgs: Vec<Vec<u8>>) -> Result<H256, OmnicError>;
+    async fn set_omnic_canister_addr(&self, caller: String, chain_id: u32, new_addr: String) -> Result<H256, OmnicError>;
+    async fn withdraw_native_fee(&self, caller: String, chain_id: u32, to: String, amount: u128) -> Result<H256, OmnicError>;
     async fn get_tx_count(&self, addr: String) -> Result<u64, OmnicError>;
     async fn get_gas_price(&self) -> Result<u64, OmnicError>;
     async fn send_raw_tx(&self, raw_tx: Vec<u8>) -> Result<Vec<u8>, OmnicError>;
     async fn get_latest_root(&self, height: Option<u64>) -> Result<H256, OmnicError>;
     async fn get_block_number(&self) -> Result<u64, OmnicError>;
+    // gas and message results of a mined tx, None if the receipt is not available yet
+    async fn get_tx_receipt(&self, txhash: String) -> Result<Option<DeliveryReceipt>, OmnicError>;
+    async fn get_balance(&self, addr: String) -> Result<u128, OmnicError>;
+    async fn get_proxy_canister_addr(&self) -> Result<String, OmnicError>;
+    // true if txs sent from addr are not mined yet
+    async fn has_pending_txs(&self, addr: String) -> Result<bool, OmnicError>;
 }
\ No newline at end of file
@@ -1,12 +1,102 @@
 
 use candid::Deserialize;
-use ic_web3::types::H256;
+use ic_web3::types::{H256, U256};
 use crate::{utils::keccak256, OmnicError};
 use crate::OmnicError::DecodeError;
 use ic_web3::ethabi::{decode, encode, ParamType, Token, Error};
 
+/// v0: abi.encode(origin, sender, nonce, destination, recipient, body), as Types.formatMessage
+pub const MESSAGE_V0: u8 = 0;
+/// v1: abi.encode(header, origin, sender, nonce, destination, recipient, extensions, body),
+/// as Types.formatMessageV1
+pub const MESSAGE_V1: u8 = 1;
+/// high bytes of the header word of versioned messages, the version is the last byte.
+/// the first word of a v0 message is a uint32 origin, so its high bytes are always zero
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"OMNC";
+
+/// deadline as unix seconds, abi encoded uint64
+pub const EXT_DEADLINE_TIMESTAMP: u16 = 1;
+/// deadline as origin chain block number, abi encoded uint64
+pub const EXT_DEADLINE_BLOCK: u16 = 2;
+/// empty, the sender asks for a notice if the message expires
+pub const EXT_NOTIFY_EXPIRY: u16 = 3;
+
+/// empty, the body is abi.encode((bytes32 recipient, bytes payload)[]), the recipient of the message is unused
+pub const EXT_BATCH: u16 = 4;
+
+/// one payload of a batch message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub recipient: H256,
+    pub payload: Vec<u8>,
+}
+
+/// results of delivering a batch entry by entry, a failed entry does not stop the rest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchOutcome {
+    pub total: usize,
+    pub delivered: usize,
+    pub failed: Vec<(usize, String)>, // entry index, error
+}
+
+impl BatchOutcome {
+    pub fn new(total: usize) -> Self {
+        BatchOutcome { total, ..Default::default() }
+    }
+
+    pub fn record(&mut self, index: usize, res: &Result<String, String>) {
+        match res {
+            Ok(_) => self.delivered += 1,
+            Err(e) => self.failed.push((index, e.clone())),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!("batch delivered {}/{}", self.delivered, self.total)
+    }
+}
+
+fn batch_type() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::FixedBytes(32), ParamType::Bytes])))
+}
+
+/// body of a batch message
+pub fn encode_batch(entries: &[BatchEntry]) -> Vec<u8> {
+    let entries = entries.iter()
+        .map(|e| Token::Tuple(vec![Token::FixedBytes(e.recipient.as_bytes().to_vec()), Token::Bytes(e.payload.clone())]))
+        .collect();
+    encode(&[Token::Array(entries)])
+}
+
+pub fn decode_batch(body: &[u8]) -> Result<Vec<BatchEntry>, OmnicError> {
+    let mut res = decode(&[batch_type()], body)?;
+    let items = res.remove(0).into_array().ok_or(DecodeError("get batch entries failed".into()))?;
+    items.into_iter().map(|item| {
+        let fields = item.into_tuple().ok_or(DecodeError("get batch entry failed".into()))?;
+        let recipient = fields[0].clone().into_fixed_bytes().ok_or(DecodeError("get entry recipient failed".into()))?;
+        let payload = fields[1].clone().into_bytes().ok_or(DecodeError("get entry payload failed".into()))?;
+        Ok(BatchEntry { recipient: H256::from_slice(&recipient), payload })
+    }).collect()
+}
+
+/// the message must not be delivered after its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deadline {
+    Timestamp(u64),   // unix seconds
+    OriginBlock(u64), // block number on the origin chain
+}
+
+/// header extension of a v1 message, unknown kinds are kept and hashed but ignored
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct Message {
+    /// envelope version, not encoded in v0 messages
+    pub version: u8,
     /// 4   SLIP-44 ID
     pub origin: u32,
     /// 32  Address in home convention
@@ -17,10 +107,32 @@ pub struct Message {
     pub destination: u32,
     /// 32  Address in destination convention
     pub recipient: H256,
+    /// v1+ header extensions
+    pub extensions: Vec<Extension>,
     /// 0+  Message contents
     pub body: Vec<u8>,
 }
 
+fn extensions_type() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Uint(16), ParamType::Bytes])))
+}
+
+fn envelope_header(version: u8) -> Vec<u8> {
+    let mut header = vec![0u8; 32];
+    header[..4].copy_from_slice(&ENVELOPE_MAGIC);
+    header[31] = version;
+    header
+}
+
+/// version of a raw message, v0 if it has no envelope header
+pub fn message_version(data: &[u8]) -> u8 {
+    if data.len() >= 32 && data[..4] == ENVELOPE_MAGIC {
+        data[31]
+    } else {
+        MESSAGE_V0
+    }
+}
+
 fn decode_body(data: &[u8]) -> Result<Vec<Token>, Error> {
     let types = vec![
         ParamType::Uint(32), ParamType::FixedBytes(32), ParamType::Uint(32), 
@@ -29,6 +141,45 @@ fn decode_body(data: &[u8]) -> Result<Vec<Token>, Error> {
     decode(&types, data)
 }
 
+fn decode_body_v1(data: &[u8]) -> Result<Vec<Token>, Error> {
+    let types = vec![
+        ParamType::FixedBytes(32), ParamType::Uint(32), ParamType::FixedBytes(32), ParamType::Uint(32),
+        ParamType::Uint(32), ParamType::FixedBytes(32), extensions_type(), ParamType::Bytes
+    ];
+    decode(&types, data)
+}
+
+fn encode_body_v1(msg: &Message) -> Vec<u8> {
+    let extensions = msg.extensions.iter()
+        .map(|e| Token::Tuple(vec![Token::Uint(e.kind.into()), Token::Bytes(e.data.clone())]))
+        .collect();
+    let tokens = [
+        Token::FixedBytes(envelope_header(msg.version)),
+        Token::Uint(msg.origin.into()),
+        Token::FixedBytes(msg.sender.as_bytes().to_vec()),
+        Token::Uint(msg.nonce.into()),
+        Token::Uint(msg.destination.into()),
+        Token::FixedBytes(msg.recipient.as_bytes().to_vec()),
+        Token::Array(extensions),
+        Token::Bytes(msg.body.clone())
+    ];
+    encode(&tokens)
+}
+
+fn decode_extensions(token: Token) -> Result<Vec<Extension>, OmnicError> {
+    let items = token.into_array().ok_or(DecodeError("get extensions failed".into()))?;
+    items.into_iter().map(|item| {
+        let fields = item.into_tuple().ok_or(DecodeError("get extension failed".into()))?;
+        let kind = fields[0].clone().into_uint().ok_or(DecodeError("get extension kind failed".into()))?;
+        // the abi decoder does not bound uint16
+        if kind > U256::from(u16::MAX) {
+            return Err(DecodeError(format!("extension kind {} out of range", kind)));
+        }
+        let data = fields[1].clone().into_bytes().ok_or(DecodeError("get extension data failed".into()))?;
+        Ok(Extension { kind: kind.as_u32() as u16, data })
+    }).collect()
+}
+
 fn encode_body(msg: &Message) -> Vec<u8> {
     let tokens = [
         Token::Uint(msg.origin.into()),
@@ -43,7 +194,37 @@ fn encode_body(msg: &Message) -> Vec<u8> {
 
 impl Message {
     pub fn from_raw(raw_bytes: Vec<u8>) -> Result<Self, OmnicError> {
-        let res = decode_body(&raw_bytes)?;
+        match message_version(&raw_bytes) {
+            MESSAGE_V0 => Self::from_raw_v0(&raw_bytes),
+            MESSAGE_V1 => Self::from_raw_v1(&raw_bytes),
+            v => Err(DecodeError(format!("unsupported message version: {}", v))),
+        }
+    }
+
+    fn from_raw_v1(raw_bytes: &[u8]) -> Result<Self, OmnicError> {
+        let mut res = decode_body_v1(raw_bytes)?;
+        let origin = res[1].clone().into_uint().ok_or(DecodeError("get origin failed".into()))?.as_u32();
+        let sender_bytes = res[2].clone().into_fixed_bytes().ok_or(DecodeError("get sender failed".into()))?;
+        let nonce = res[3].clone().into_uint().ok_or(DecodeError("get nonce failed".into()))?.as_u32();
+        let destination = res[4].clone().into_uint().ok_or(DecodeError("get destination failed".into()))?.as_u32();
+        let recipient_bytes = res[5].clone().into_fixed_bytes().ok_or(DecodeError("get recipient failed".into()))?;
+        let extensions = decode_extensions(res.remove(6))?;
+        let body = res[6].clone().into_bytes().ok_or(DecodeError("get body failed".into()))?;
+
+        Ok(Message {
+            version: MESSAGE_V1,
+            origin,
+            sender: H256::from_slice(&sender_bytes),
+            nonce,
+            destination,
+            recipient: H256::from_slice(&recipient_bytes),
+            extensions,
+            body,
+        })
+    }
+
+    fn from_raw_v0(raw_bytes: &[u8]) -> Result<Self, OmnicError> {
+        let res = decode_body(raw_bytes)?;
         let origin = res[0].clone().into_uint().ok_or(DecodeError("get origin failed".into()))?.as_u32();
         let sender_bytes = res[1].clone().into_fixed_bytes().ok_or(DecodeError("get sender failed".into()))?;
         let sender = H256::from_slice(&sender_bytes);
@@ -54,22 +235,90 @@ impl Message {
         let body = res[5].clone().into_bytes().ok_or(DecodeError("get body failed".into()))?;
 
         Ok(Message {
+            version: MESSAGE_V0,
             origin,
             sender,
             nonce,
             destination,
             recipient,
+            extensions: vec![],
             body,
         })
     }
 }
 
 impl Message {
+    /// abi encoded message in its envelope version, as Types.formatMessage / formatMessageV1
+    pub fn to_raw(&self) -> Vec<u8> {
+        match self.version {
+            MESSAGE_V0 => encode_body(&self),
+            _ => encode_body_v1(&self),
+        }
+    }
+
     /// Convert the message to a leaf
     pub fn to_leaf(&self) -> H256 {
-        let raw = encode_body(&self);
+        let raw = self.to_raw();
         keccak256(&raw).into()
     }
+
+    pub fn deadline(&self) -> Result<Option<Deadline>, OmnicError> {
+        let decode_u64 = |data: &[u8]| -> Result<u64, OmnicError> {
+            let res = decode(&[ParamType::Uint(64)], data)?;
+            let v = res[0].clone().into_uint().ok_or(DecodeError("get deadline failed".into()))?;
+            if v.bits() > 64 {
+                return Err(DecodeError("deadline overflow".into()));
+            }
+            Ok(v.low_u64())
+        };
+        if let Some(data) = self.extension(EXT_DEADLINE_TIMESTAMP) {
+            return Ok(Some(Deadline::Timestamp(decode_u64(data)?)));
+        }
+        if let Some(data) = self.extension(EXT_DEADLINE_BLOCK) {
+            return Ok(Some(Deadline::OriginBlock(decode_u64(data)?)));
+        }
+        Ok(None)
+    }
+
+    pub fn is_batch(&self) -> bool {
+        self.extension(EXT_BATCH).is_some()
+    }
+
+    pub fn batch_entries(&self) -> Result<Vec<BatchEntry>, OmnicError> {
+        decode_batch(&self.body)
+    }
+
+    /// number of handleMessage calls delivering this message, one for each entry of a batch
+    pub fn deliveries(&self) -> usize {
+        if !self.is_batch() {
+            return 1;
+        }
+        self.batch_entries().map(|e| e.len()).unwrap_or(1)
+    }
+
+    /// the message delivered for one entry of a batch, it has the fields of the batch
+    /// except the recipient and body, and no extensions
+    pub fn entry_message(&self, entry: &BatchEntry) -> Message {
+        Message {
+            version: MESSAGE_V0,
+            origin: self.origin,
+            sender: self.sender,
+            nonce: self.nonce,
+            destination: self.destination,
+            recipient: entry.recipient,
+            extensions: vec![],
+            body: entry.payload.clone(),
+        }
+    }
+
+    pub fn notify_expiry(&self) -> bool {
+        self.extension(EXT_NOTIFY_EXPIRY).is_some()
+    }
+
+    /// data of the first header extension of `kind`
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions.iter().find(|e| e.kind == kind).map(|e| e.data.as_slice())
+    }
 }
 
 impl std::fmt::Display for Message {
@@ -80,4 +329,185 @@ impl std::fmt::Display for Message {
             self.origin, self.destination, self.nonce,
         )
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // abi words built by hand, independent of ethabi
+    fn word(v: u64) -> Vec<u8> {
+        let mut w = vec![0u8; 32];
+        w[24..].copy_from_slice(&v.to_be_bytes());
+        w
+    }
+
+    fn padded(data: &[u8]) -> Vec<u8> {
+        let mut out = word(data.len() as u64);
+        out.extend_from_slice(data);
+        out.resize(32 + (data.len() + 31) / 32 * 32, 0);
+        out
+    }
+
+    fn message(version: u8) -> Message {
+        Message {
+            version,
+            origin: 5,
+            sender: H256::repeat_byte(0x11),
+            nonce: 7,
+            destination: 0,
+            recipient: H256::repeat_byte(0x22),
+            extensions: vec![],
+            body: b"hi".to_vec(),
+        }
+    }
+
+    fn assert_same(a: &Message, b: &Message) {
+        assert_eq!(a.version, b.version);
+        assert_eq!((a.origin, a.sender, a.nonce), (b.origin, b.sender, b.nonce));
+        assert_eq!((a.destination, a.recipient), (b.destination, b.recipient));
+        assert_eq!(a.extensions, b.extensions);
+        assert_eq!(a.body, b.body);
+    }
+
+    #[test]
+    fn it_round_trips_both_versions() {
+        let v0 = message(MESSAGE_V0);
+        assert_same(&Message::from_raw(v0.to_raw()).unwrap(), &v0);
+
+        let mut v1 = message(MESSAGE_V1);
+        v1.extensions = vec![
+            Extension { kind: EXT_DEADLINE_TIMESTAMP, data: word(100) },
+            Extension { kind: 0xffff, data: vec![1, 2, 3] },
+        ];
+        let raw = v1.to_raw();
+        assert_eq!(message_version(&raw), MESSAGE_V1);
+        let decoded = Message::from_raw(raw).unwrap();
+        assert_same(&decoded, &v1);
+        assert_eq!(decoded.deadline().unwrap(), Some(Deadline::Timestamp(100)));
+        // unknown kinds are kept, so the leaf is unchanged
+        assert_eq!(decoded.to_leaf(), v1.to_leaf());
+        assert_ne!(v1.to_leaf(), message(MESSAGE_V1).to_leaf());
+    }
+
+    #[test]
+    fn it_decodes_v0_bytes() {
+        // Types.formatMessage(5, 0x11.., 7, 0, 0x22.., "hi")
+        let raw = [
+            word(5), vec![0x11; 32], word(7), word(0), vec![0x22; 32], word(0xc0), padded(b"hi"),
+        ].concat();
+        assert_eq!(message_version(&raw), MESSAGE_V0);
+        let m = Message::from_raw(raw.clone()).unwrap();
+        assert_same(&m, &message(MESSAGE_V0));
+        assert_eq!(m.to_raw(), raw);
+        assert!(m.deadline().unwrap().is_none());
+    }
+
+    #[test]
+    fn it_matches_the_solidity_encoding() {
+        let mut m = message(MESSAGE_V1);
+        m.extensions = vec![Extension { kind: EXT_DEADLINE_BLOCK, data: word(100) }];
+        // Types.formatMessageV1(5, 0x11.., 7, 0, 0x22.., [Extension(2, abi.encode(uint64(100)))], "hi")
+        let mut header = vec![0u8; 32];
+        header[..4].copy_from_slice(b"OMNC");
+        header[31] = 1;
+        let extensions = [
+            word(1),    // array length
+            word(0x20), // offset of the dynamic tuple
+            word(2), word(0x40), padded(&word(100)),
+        ].concat();
+        let raw = [
+            header, word(5), vec![0x11; 32], word(7), word(0), vec![0x22; 32],
+            word(0x100), word(0x100 + extensions.len() as u64),
+            extensions, padded(b"hi"),
+        ].concat();
+        assert_eq!(m.to_raw(), raw);
+        assert_eq!(m.to_leaf(), H256::from(keccak256(&raw)));
+
+        let v0 = message(MESSAGE_V0);
+        assert_eq!(v0.to_leaf(), H256::from(keccak256(&v0.to_raw())));
+    }
+
+    #[test]
+    fn it_rejects_extension_kinds_over_u16() {
+        let mut m = message(MESSAGE_V1);
+        m.extensions = vec![Extension { kind: EXT_DEADLINE_TIMESTAMP, data: word(100) }];
+        let raw = m.to_raw();
+        // header, 5 fields, 2 offsets, array length, tuple offset, then the kind
+        let at = 32 * 10;
+        assert_eq!(raw[at..at + 32], word(EXT_DEADLINE_TIMESTAMP as u64)[..]);
+        for kind in vec![word(0x10001), vec![0xff; 32]] {
+            let mut raw = raw.clone();
+            raw[at..at + 32].copy_from_slice(&kind);
+            assert!(matches!(Message::from_raw(raw), Err(DecodeError(e)) if e.contains("extension kind")));
+        }
+    }
+
+    fn batch_message(entries: &[BatchEntry]) -> Message {
+        let mut m = message(MESSAGE_V1);
+        m.extensions = vec![Extension { kind: EXT_BATCH, data: vec![] }];
+        m.body = encode_batch(entries);
+        m
+    }
+
+    #[test]
+    fn it_round_trips_batches() {
+        let entries = vec![
+            BatchEntry { recipient: H256::repeat_byte(0x33), payload: b"a".to_vec() },
+            BatchEntry { recipient: H256::repeat_byte(0x44), payload: vec![] },
+            BatchEntry { recipient: H256::repeat_byte(0x33), payload: vec![7; 100] },
+        ];
+        let m = Message::from_raw(batch_message(&entries).to_raw()).unwrap();
+        assert!(m.is_batch());
+        assert_eq!(m.batch_entries().unwrap(), entries);
+
+        let em = m.entry_message(&entries[2]);
+        assert_eq!((em.version, em.origin, em.sender, em.nonce), (MESSAGE_V0, 5, m.sender, 7));
+        assert_eq!((em.recipient, em.body.clone()), (entries[2].recipient, entries[2].payload.clone()));
+        assert!(em.extensions.is_empty() && !em.is_batch());
+        assert_eq!(m.deliveries(), 3);
+        assert_eq!(em.deliveries(), 1);
+    }
+
+    #[test]
+    fn it_handles_empty_and_malformed_batches() {
+        let m = batch_message(&[]);
+        assert!(m.batch_entries().unwrap().is_empty());
+        assert_eq!(BatchOutcome::new(0).summary(), "batch delivered 0/0");
+
+        // a non batch body
+        let mut m = batch_message(&[]);
+        m.body = b"hi".to_vec();
+        assert!(m.batch_entries().is_err());
+        assert_eq!(m.deliveries(), 1);
+        assert!(!message(MESSAGE_V1).is_batch());
+        assert_eq!(batch_message(&[]).deliveries(), 0);
+    }
+
+    #[test]
+    fn it_records_failed_entries_separately() {
+        let mut outcome = BatchOutcome::new(3);
+        outcome.record(0, &Ok("ok".into()));
+        outcome.record(1, &Err("rejected by app: sender not allowed".into()));
+        outcome.record(2, &Ok("ok".into()));
+        assert_eq!(outcome.delivered, 2);
+        assert_eq!(outcome.failed, vec![(1, "rejected by app: sender not allowed".to_string())]);
+        assert_eq!(outcome.summary(), "batch delivered 2/3");
+    }
+
+    #[test]
+    fn it_rejects_unknown_versions_and_truncated_messages() {
+        let mut raw = message(MESSAGE_V1).to_raw();
+        raw[31] = 2;
+        assert!(matches!(Message::from_raw(raw), Err(DecodeError(e)) if e.contains("unsupported message version")));
+
+        let raw = message(MESSAGE_V1).to_raw();
+        assert!(Message::from_raw(raw[..32].to_vec()).is_err());
+        assert!(Message::from_raw(raw[..raw.len() - 32].to_vec()).is_err());
+        // a header cut short is not an envelope, and not a valid v0 message either
+        assert_eq!(message_version(b"OMNC"), MESSAGE_V0);
+        assert!(Message::from_raw(b"OMNC".to_vec()).is_err());
+
+        let raw = message(MESSAGE_V0).to_raw();
+        assert!(Message::from_raw(raw[..6 * 32].to_vec()).is_err());
+    }
+}
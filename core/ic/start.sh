@@ -9,7 +9,7 @@ dfx canister create demo
 dfx build proxy
 dfx build demo
 
-dfx canister install proxy
+dfx canister install proxy --argument "(\"dfx_test_key\")"
 dfx canister install demo
 
 dfx canister --network ic call proxy add_chain "(5:nat32, vec {\"https://eth-goerli.g.alchemy.com/v2/0QCHDmgIEFRV48r1U1QbtOyFInib3ZAm\"}, principal \"$(dfx canister --network ic id goerli-gateway)\", \"c7D718dC3C9248c91813A98dCbFEC6CF57619520\", 7685333:nat64)"
@@ -18,7 +18,9 @@ dfx canister --network ic call proxy add_chain "(80001:nat32, vec {\"https://pol
 
 dfx canister --network ic call proxy set_canister_addrs
 
-dfx canister --network ic call proxy get_canister_addr "(variant {Evm})"
+dfx canister --network ic call proxy get_canister_addr "(5:nat32)"
+
+dfx canister --network ic call proxy get_canister_addr "(80001:nat32)"
 
 dfx canister --network ic call proxy get_chains
 